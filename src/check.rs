@@ -0,0 +1,376 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use toml::Spanned;
+
+use crate::config;
+use crate::keys;
+
+// Diagnostic is a problem of the config, at its line and column, both
+// counted from 1
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+// Severity tells whether the config can't be used as is, or can be with
+// some of its mappings never firing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// The mappings of the config with where their keys are, to point the
+// diagnostics at them
+#[derive(Deserialize)]
+struct SpannedConfig {
+    leader_key: Spanned<String>,
+    groups: Vec<SpannedGroup>,
+}
+
+#[derive(Deserialize)]
+struct SpannedGroup {
+    name: String,
+    mappings: Vec<SpannedMapping>,
+}
+
+#[derive(Deserialize)]
+struct SpannedMapping {
+    keys: Spanned<String>,
+}
+
+// check parses the config and reports what's wrong with it, in the order
+// of the lines. A config that fails to parse only has the parse error,
+// otherwise the leader key is checked to be a key name, or a chord, and the
+// mappings are checked for:
+//
+// - keys not written like keys::parse expects, or no key of a US keyboard
+//   sends, like "!", when keys are named by their position
+// - keys already mapped, by the same group or another one
+// - keys that begin with a shorter mapping, which always fires first. The
+//   config still works, so it's only a warning
+pub fn check(content: &str) -> Vec<Diagnostic> {
+    let (key_names, activation) = match toml::from_str::<config::Config>(content) {
+        Ok(config) => (config.input.key_names, config.leader_activation),
+        Err(err) => return vec![parse_error(content, err)],
+    };
+
+    let config: SpannedConfig = match toml::from_str(content) {
+        Ok(config) => config,
+        Err(err) => return vec![parse_error(content, err)],
+    };
+
+    let mappings: Vec<(&str, &Spanned<String>)> = config
+        .groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .mappings
+                .iter()
+                .map(move |mapping| (group.name.as_str(), &mapping.keys))
+        })
+        .collect();
+
+    let mut diagnostics = vec![];
+    if let Some(message) = leader_key_problem(config.leader_key.get_ref(), activation) {
+        diagnostics.push(diagnostic(content, config.leader_key.span(), message));
+    }
+
+    let mut parsed: Vec<(&str, &Spanned<String>, Vec<String>)> = vec![];
+    for (group, keys) in mappings.iter() {
+        let span = keys.span();
+
+        if keys.get_ref().is_empty() {
+            diagnostics.push(diagnostic(content, span.clone(), "empty keys".to_string()));
+        }
+
+        match keys::parse(keys.get_ref()) {
+            Ok(tokens) => parsed.push((*group, *keys, tokens)),
+            Err(err) => diagnostics.push(diagnostic(content, span.clone(), err)),
+        }
+
+        if key_names == config::KeyNames::Position {
+            if let Some(c) = keys::untypable(keys.get_ref()) {
+                diagnostics.push(diagnostic(
+                    content,
+                    span,
+                    format!(
+                        "{:?} can never be typed, keys are named by their position on a US keyboard",
+                        c
+                    ),
+                ));
+            }
+        }
+    }
+
+    // Mappings are compared by their tokens, "o<S-f>" and "oF" are the same
+    let mut all_keys: BTreeMap<&[String], &str> = BTreeMap::new();
+    for (_, keys, tokens) in parsed.iter() {
+        all_keys.entry(tokens).or_insert(keys.get_ref());
+    }
+
+    let mut seen: HashMap<&[String], (&str, Range<usize>)> = HashMap::new();
+    for (group, keys, tokens) in parsed.iter() {
+        let span = keys.span();
+
+        if let Some((first_group, first_span)) = seen.get(tokens.as_slice()) {
+            diagnostics.push(diagnostic(
+                content,
+                span.clone(),
+                format!(
+                    "{:?} is already mapped by {:?} on line {}",
+                    keys.get_ref(),
+                    first_group,
+                    position(content, first_span.start).0
+                ),
+            ));
+        } else {
+            seen.insert(tokens, (group, span.clone()));
+        }
+
+        for (other, other_keys) in all_keys.iter() {
+            if !other.is_empty() && other.len() < tokens.len() && tokens.starts_with(other) {
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    ..diagnostic(
+                        content,
+                        span.clone(),
+                        format!(
+                            "{:?} can never fire, {:?} fires as soon as it's typed",
+                            keys.get_ref(),
+                            other_keys
+                        ),
+                    )
+                });
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    diagnostics
+}
+
+// leader_key_problem tells what's wrong with the leader key: it's a key
+// name, or a single key written like the keys of the mappings for a chord
+fn leader_key_problem(leader_key: &str, activation: config::LeaderActivation) -> Option<String> {
+    if activation == config::LeaderActivation::Chord {
+        return match keys::parse(leader_key) {
+            Ok(tokens) if tokens.len() == 1 => None,
+            Ok(_) => Some(format!(
+                "{:?} is not a chord, write a single key like \"<C-space>\"",
+                leader_key
+            )),
+            Err(err) => Some(err),
+        };
+    }
+
+    if keys::is_key_name(leader_key) {
+        None
+    } else {
+        Some(format!(
+            "{:?} is not a key name, use one like \"option\", \"rightoption\" or \"f18\"",
+            leader_key
+        ))
+    }
+}
+
+fn parse_error(content: &str, err: toml::de::Error) -> Diagnostic {
+    let span = err.span().unwrap_or(0..0);
+    diagnostic(content, span, err.message().to_string())
+}
+
+fn diagnostic(content: &str, span: Range<usize>, message: String) -> Diagnostic {
+    let (line, column) = position(content, span.start);
+    Diagnostic {
+        line,
+        column,
+        severity: Severity::Error,
+        message,
+    }
+}
+
+// position turns a byte offset of the content into its line and column
+fn position(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+
+    (line, column)
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+// Diagnostics are printed as "line:column: message", with "warning: "
+// before the message of a warning, prefixed with the path of the config
+// when reported
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        if self.severity == Severity::Warning {
+            write!(f, "warning: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(content: &str) -> Vec<String> {
+        check(content).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn check_passes_valid_config() {
+        let content = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+"#;
+
+        assert!(check(content).is_empty());
+    }
+
+    #[test]
+    fn check_reports_parse_error_position() {
+        let content = "leader_key = \"option\"\ngroups = [\n  { name = \"x\", mappings = 3 },\n]\n";
+
+        assert_eq!(
+            messages(content),
+            ["3:28: invalid type: integer `3`, expected a sequence"]
+        );
+    }
+
+    #[test]
+    fn check_reports_mapping_problems() {
+        let content = r#"
+leader_key = "option"
+
+[input]
+key_names = "position"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "o"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "ovs"
+  kind = "Application"
+  command = "Visual Studio Code"
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "o"
+  kind = "Command"
+  command = "true"
+
+  [[groups.mappings]]
+  keys = "r!"
+  kind = "Command"
+  command = "true"
+"#;
+
+        assert_eq!(
+            messages(content),
+            [
+                r#"16:10: warning: "ovs" can never fire, "o" fires as soon as it's typed"#,
+                r#"24:10: "o" is already mapped by "Open Applications" on line 11"#,
+                r#"29:10: '!' can never be typed, keys are named by their position on a US keyboard"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn check_reports_unknown_leader_key() {
+        let content = r#"leader_key = "right option"
+groups = []
+"#;
+
+        assert_eq!(
+            messages(content),
+            [
+                r#"1:14: "right option" is not a key name, use one like "option", "rightoption" or "f18""#
+            ]
+        );
+    }
+
+    #[test]
+    fn check_reports_leader_key_that_is_not_a_chord() {
+        let content = r#"leader_key = "<C-space>x"
+leader_activation = "chord"
+groups = []
+"#;
+
+        assert_eq!(
+            messages(content),
+            [r#"1:14: "<C-space>x" is not a chord, write a single key like "<C-space>""#]
+        );
+        assert!(check(&content.replace("x\"", "\"")).is_empty());
+    }
+
+    #[test]
+    fn check_compares_keys_by_notation() {
+        let content = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "oF"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "o<S-f>"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "<C-o>"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "<C-o><space"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "é"
+  kind = "Application"
+  command = "Finder"
+"#;
+
+        assert_eq!(
+            messages(content),
+            [
+                r#"13:10: "o<S-f>" is already mapped by "Open Applications" on line 8"#,
+                r#"23:10: "<space" misses its closing '>'"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Clock is where the sequence recorder gets the time from when it has no
+// key event at hand, e.g. to tell if a sequence timed out while waiting
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+// SystemClock is the real time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// ManualClock only moves when told to, so timing can be tested without
+// sleeping. Its clones share the same time.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
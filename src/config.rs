@@ -1,50 +1,419 @@
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 
+// How long a sequence waits for the next key, unless the config says
+// otherwise
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+// How long a tap of the leader key may take, unless the config says
+// otherwise
+pub const DEFAULT_TAP_MS: u64 = 500;
+
 #[derive(Deserialize)]
 pub struct Config {
+    // The key starting a sequence, or the key and its modifiers like
+    // "<C-space>" when activated as a chord
     pub leader_key: String,
+    #[serde(default)]
+    pub leader_activation: LeaderActivation,
+    // Milliseconds a tap of the leader key may take, down to up, and
+    // between the two taps of a double tap
+    #[serde(default = "default_tap_ms")]
+    pub leader_tap_ms: u64,
+    // Milliseconds a sequence waits for the next key before it's reset,
+    // groups and mappings can override it
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub on_abort: AbortAction,
+    #[serde(default)]
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub input: InputConfig,
+    #[serde(default)]
+    pub hints: HintsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    // Labels of the keys that begin longer sequences, e.g. "o" is "Open
+    // apps". Prefixes without one are labeled by their groups.
+    #[serde(default)]
+    pub prefixes: HashMap<String, String>,
     pub groups: Vec<Group>,
 }
 
+// LeaderActivation is the way the leader key starts a sequence
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderActivation {
+    // Typed down and up on its own, with no other key in between
+    #[default]
+    Tap,
+    // Tapped twice in a row
+    DoubleTap,
+    // Held down while the keys of the sequence are typed, releasing it
+    // ends the sequence
+    Hold,
+    // Typed with its modifiers, like "<C-space>"
+    Chord,
+}
+
+// AbortAction decides what happens to the keys typed after the leader key
+// once they can't match any mapping anymore
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AbortAction {
+    // Throw the keys away
+    Drop,
+    // Send the keys on to the application, as if whichkey wasn't running
+    #[default]
+    Replay,
+}
+
+// InputConfig tells the input backend how to name the keys and, on Linux,
+// which devices to capture, macOS captures every keyboard
+#[derive(Deserialize, Debug, Default)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct InputConfig {
+    #[serde(default)]
+    pub backend: Backend,
+    // Paths or names of the keyboards to grab, all keyboards when empty,
+    // only used by the evdev backend
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub key_names: KeyNames,
+    // XKB layout and variant of the keyboards, e.g. "fr", or "us" and
+    // "dvorak", only used by the evdev backend. $XKB_DEFAULT_LAYOUT and
+    // $XKB_DEFAULT_VARIANT by default.
+    pub layout: Option<String>,
+    pub variant: Option<String>,
+}
+
+// KeyNames tells if the keys of the mappings are the characters the
+// keyboard layout types, or the keys at their position on a US keyboard
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyNames {
+    // "a" is the key typing "a", wherever it is
+    #[default]
+    Layout,
+    // "a" is the key right of caps lock, whatever it types
+    Position,
+}
+
+// Backend is the way whichkey captures the keys on Linux
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub enum Backend {
+    // Grab the keyboards through evdev, works everywhere but needs access
+    // to /dev/input and /dev/uinput
+    #[default]
+    Evdev,
+    // Watch the keys through the RECORD extension of the X server, and
+    // grab the keyboard only while a sequence is under way
+    X11,
+}
+
+// LoggingConfig tells where whichkey logs and what the log may say about
+// the keys typed. By default only the keys of a sequence under way are
+// logged, by name, so the log never holds what's typed in between, like
+// passwords.
+#[derive(Deserialize, Debug)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub level: LogLevel,
+    // The log file, ~/whichkey.log by default
+    pub file: Option<PathBuf>,
+    // Bytes the log file grows to before it's rotated
+    #[serde(default = "default_max_log_size")]
+    pub max_size: u64,
+    // Rotated log files kept, <file>.1 being the latest
+    #[serde(default = "default_max_log_files")]
+    pub max_files: usize,
+    #[serde(default)]
+    pub format: LogFormat,
+    // Log every key event, with the raw keycodes where the backend has
+    // them, to debug the key names
+    #[serde(default)]
+    pub debug_keys: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, with the time, level, target and message
+    Json,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: LogLevel::default(),
+            file: None,
+            max_size: default_max_log_size(),
+            max_files: default_max_log_files(),
+            format: LogFormat::default(),
+            debug_keys: false,
+        }
+    }
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+// HintsConfig tells how the keys that can come next in a sequence are
+// shown
+#[derive(Deserialize, Debug, Default)]
+pub struct HintsConfig {
+    #[serde(default)]
+    pub display: HintDisplayKind,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HintDisplayKind {
+    // Don't show any hints
+    #[default]
+    None,
+    // Print the hints in the terminal whichkey runs in
+    Terminal,
+    // Show the hints in a window of the X server, on Linux
+    X11,
+}
+
 #[derive(Deserialize)]
 pub struct Group {
     pub name: String,
+    // Overrides the timeout of the config for the mappings of the group
+    pub timeout_ms: Option<u64>,
     pub mappings: Vec<Mapping>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Mapping {
     pub keys: String,
-    pub kind: String,
-    pub command: String,
+    #[serde(flatten)]
+    pub action: Action,
+    // What the mapping does, shown in the hints instead of the command
+    pub description: Option<String>,
+    // Overrides the timeout of the group and the config for this mapping
+    pub timeout_ms: Option<u64>,
+}
+
+// Action is what a mapping does once its keys are typed, picked by its
+// `kind`. An unknown kind fails to load with the config.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind")]
+pub enum Action {
+    // Open the application named by the command
+    Application { command: String },
+    // Run the command with sh
+    Command { command: String },
+}
+
+impl Mapping {
+    // label is what the mapping shows in the hints and listings, its
+    // description or else its command
+    pub fn label(&self) -> &str {
+        self.description
+            .as_deref()
+            .unwrap_or_else(|| self.action.command())
+    }
+}
+
+impl Action {
+    pub fn command(&self) -> &str {
+        match self {
+            Action::Application { command } | Action::Command { command } => command,
+        }
+    }
+}
+
+// Actions are printed by kind and command, e.g. "Application Finder"
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Application { command } => write!(f, "Application {}", command),
+            Action::Command { command } => write!(f, "Command {}", command),
+        }
+    }
+}
+
+fn default_tap_ms() -> u64 {
+    DEFAULT_TAP_MS
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+fn default_max_log_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_log_files() -> usize {
+    3
+}
+
+// path resolves where the config is, the first of:
+//
+// - the path given with --config
+// - $WHICHKEY_CONFIG
+// - $XDG_CONFIG_HOME/whichkey/config.toml
+// - $HOME/.config/whichkey/config.toml
+pub fn path(flag: Option<&Path>) -> Result<PathBuf, String> {
+    resolve_path(flag, |name| std::env::var_os(name))
+}
+
+fn resolve_path(
+    flag: Option<&Path>,
+    env: impl Fn(&str) -> Option<OsString>,
+) -> Result<PathBuf, String> {
+    // Empty variables count as unset, like for the XDG base directories
+    let env = |name| env(name).filter(|value: &OsString| !value.is_empty());
+
+    if let Some(path) = flag {
+        return Ok(path.to_path_buf());
+    }
+    if let Some(path) = env("WHICHKEY_CONFIG") {
+        return Ok(PathBuf::from(path));
+    }
+
+    let config_home = match (env("XDG_CONFIG_HOME"), env("HOME")) {
+        (Some(config_home), _) => PathBuf::from(config_home),
+        (None, Some(home)) => PathBuf::from(home).join(".config"),
+        (None, None) => {
+            return Err(
+                "Can't tell where the config is, set HOME, XDG_CONFIG_HOME or WHICHKEY_CONFIG, or pass --config"
+                    .to_string(),
+            )
+        }
+    };
+
+    Ok(config_home.join("whichkey").join("config.toml"))
 }
 
 impl Config {
-    pub fn new() -> Self {
-        let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let config_file_path = PathBuf::from(home_dir)
-            .join(".config")
-            .join("whichkey")
-            .join("config.toml");
-        let content = match fs::read_to_string(config_file_path) {
+    // load reads the config at the path, whichkey can't go on without it
+    pub fn load(path: &Path) -> Self {
+        let content = match fs::read_to_string(path) {
             Ok(c) => c,
             Err(e) => {
-                log::error!("Failed to read file: {}", e);
+                eprintln!("Failed to read {}: {}", path.display(), e);
                 process::exit(1);
             }
         };
 
-        let config = match toml::from_str(&content) {
+        match toml::from_str(&content) {
             Ok(c) => c,
             Err(e) => {
-                log::error!("Failed to deserialize config: {}", e);
+                eprintln!("Failed to deserialize config, see `whichkey check`: {}", e);
                 process::exit(1);
             }
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(kind: &str) -> String {
+        format!(
+            r#"leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "{}"
+  command = "Finder"
+"#,
+            kind
+        )
+    }
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| OsString::from(value))
+        }
+    }
+
+    #[test]
+    fn path_prefers_flag_then_env_then_xdg() {
+        let vars = [
+            ("WHICHKEY_CONFIG", "/env/config.toml"),
+            ("XDG_CONFIG_HOME", "/xdg"),
+            ("HOME", "/home/me"),
+        ];
+        let flag = Path::new("/flag/config.toml");
+
+        assert_eq!(resolve_path(Some(flag), env(&vars)), Ok(flag.to_path_buf()));
+        assert_eq!(
+            resolve_path(None, env(&vars)),
+            Ok(PathBuf::from("/env/config.toml"))
+        );
+        assert_eq!(
+            resolve_path(None, env(&vars[1..])),
+            Ok(PathBuf::from("/xdg/whichkey/config.toml"))
+        );
+        assert_eq!(
+            resolve_path(None, env(&[("XDG_CONFIG_HOME", ""), ("HOME", "/home/me")])),
+            Ok(PathBuf::from("/home/me/.config/whichkey/config.toml"))
+        );
+        assert!(resolve_path(None, env(&[])).is_err());
+    }
+
+    #[test]
+    fn mapping_kind_picks_action() {
+        let config: Config = toml::from_str(&mapping("Application")).unwrap();
+
+        assert_eq!(
+            config.groups[0].mappings[0].action,
+            Action::Application {
+                command: "Finder".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_mapping_kind_fails_with_line() {
+        let err = toml::from_str::<Config>(&mapping("command")).err().unwrap();
 
-        return config;
+        let message = err.to_string();
+        assert!(message.contains("line 6"), "{}", message);
+        assert!(message.contains("unknown variant `command`"), "{}", message);
     }
 }
@@ -0,0 +1,504 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::health;
+use crate::input::{KeyEvent, KeyEventHandler, Verdict};
+use crate::reload;
+use crate::sequence::{Event, KeyStrokeRecorder};
+
+// Request is a line of JSON sent to the control socket, e.g.
+//
+//     {"command": "trigger", "keys": "of"}
+//
+// Every request gets a line back, {"ok": true, ...} or {"ok": false,
+// "error": "..."}. After subscribe-events, the events follow one per line
+// until the connection is closed.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    // Read the config again, it's kept if it has problems
+    Reload,
+    // Let every key through until resumed
+    Pause,
+    Resume,
+    Status,
+    ListBindings,
+    // Fire the mapping of the keys, as if typed after the leader key, or
+    // only tell which one it is on a dry run
+    Trigger {
+        keys: String,
+        #[serde(default)]
+        dry_run: bool,
+    },
+    SubscribeEvents,
+}
+
+// SharedRecorder is the recorder the input backend hands the key events
+// to, shared with the control socket
+#[derive(Clone)]
+pub struct SharedRecorder(pub Arc<Mutex<KeyStrokeRecorder>>);
+
+impl KeyEventHandler for SharedRecorder {
+    fn handle(&mut self, event: &KeyEvent) -> Verdict {
+        self.0.lock().unwrap().handle(event)
+    }
+
+    fn is_pending(&self) -> bool {
+        self.0.lock().unwrap().is_pending()
+    }
+}
+
+// How long an event waits for a subscriber to take it, one that doesn't
+// read its events is dropped instead of holding the others back
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// socket_path is where the running whichkey listens, in the runtime
+// directory of the user if there is one
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("whichkey.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_default();
+            std::env::temp_dir().join(format!("whichkey-{}.sock", user))
+        }
+    }
+}
+
+// listen binds the control socket, only the user can connect to it. A
+// socket left behind by a whichkey that's gone is replaced. It's created
+// with a umask that only leaves the user's permissions, so there's no
+// moment the other users can connect to it.
+pub fn listen(path: &Path) -> Result<UnixListener, Error> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("whichkey is already running on {}", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // umask only swaps the mask of the process, it can't fail
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    listener
+}
+
+// Server answers the requests of the control socket
+#[derive(Clone)]
+pub struct Server {
+    recorder: SharedRecorder,
+    config_path: PathBuf,
+    subscribers: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+impl Server {
+    pub fn new(recorder: SharedRecorder, config_path: PathBuf) -> Self {
+        Server {
+            recorder,
+            config_path,
+            subscribers: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    // serve answers each connection on its own thread, and sends the
+    // events of the recorder to the subscribers
+    pub fn serve(self, listener: UnixListener, events: Receiver<Event>) {
+        let subscribers = self.subscribers.clone();
+        thread::spawn(move || {
+            for event in events {
+                let line = event_json(&event).to_string();
+                // Written without the lock, so subscribing doesn't wait on a
+                // slow subscriber
+                let mut streams = std::mem::take(&mut *subscribers.lock().unwrap());
+                streams.retain_mut(|stream| writeln!(stream, "{}", line).is_ok());
+                subscribers.lock().unwrap().append(&mut streams);
+            }
+        });
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("Control socket failed: {}", err);
+                        return;
+                    }
+                };
+
+                let server = self.clone();
+                thread::spawn(move || {
+                    if let Err(err) = server.answer(stream) {
+                        log::debug!("Control connection closed: {}", err);
+                    }
+                });
+            }
+        });
+    }
+
+    fn answer(&self, stream: UnixStream) -> Result<(), Error> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let response = match serde_json::from_str(&line) {
+                Ok(Request::SubscribeEvents) => {
+                    writeln!(writer, "{}", json!({"ok": true}))?;
+                    writer.set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT))?;
+                    self.subscribers.lock().unwrap().push(writer.try_clone()?);
+                    continue;
+                }
+                Ok(request) => self.respond(request),
+                Err(err) => json!({"ok": false, "error": format!("Invalid request: {}", err)}),
+            };
+            writeln!(writer, "{}", response)?;
+        }
+
+        Ok(())
+    }
+
+    // respond answers the request. The key events wait for the recorder
+    // while it's locked, so it's locked only once the request is done with
+    // the disk, and no longer than it takes to use it.
+    fn respond(&self, request: Request) -> Value {
+        let recorder = || self.recorder.0.lock().unwrap();
+        match request {
+            Request::Reload => match reload::load(&self.config_path) {
+                Ok(config) => {
+                    recorder().reload_with(config);
+                    json!({"ok": true})
+                }
+                Err(errors) => json!({"ok": false, "error": errors.join("\n")}),
+            },
+            Request::Pause => {
+                recorder().set_paused(true);
+                json!({"ok": true})
+            }
+            Request::Resume => {
+                recorder().set_paused(false);
+                json!({"ok": true})
+            }
+            Request::Status => {
+                let input = health_json(&health::HEALTH.stats());
+                let recorder = recorder();
+                json!({
+                    "ok": true,
+                    "paused": recorder.is_paused(),
+                    "pending": recorder.is_pending(),
+                    "leader_key": recorder.config().leader_key,
+                    "config": self.config_path,
+                    "input": input,
+                })
+            }
+            Request::ListBindings => {
+                let bindings: Vec<Value> = recorder()
+                    .config()
+                    .groups
+                    .iter()
+                    .flat_map(|group| {
+                        group
+                            .mappings
+                            .iter()
+                            .map(|mapping| binding_json(&group.name, mapping))
+                    })
+                    .collect();
+                json!({"ok": true, "bindings": bindings})
+            }
+            Request::Trigger {
+                keys,
+                dry_run: true,
+            } => match recorder().resolve(&keys) {
+                Ok(mapping) => json!({"ok": true, "mapping": mapping_json(mapping)}),
+                Err(err) => json!({"ok": false, "error": err}),
+            },
+            Request::Trigger {
+                keys,
+                dry_run: false,
+            } => match recorder().trigger(&keys) {
+                Ok(mapping) => json!({"ok": true, "fired": mapping_json(&mapping)}),
+                Err(err) => json!({"ok": false, "error": err}),
+            },
+            Request::SubscribeEvents => unreachable!("subscriptions are answered by answer"),
+        }
+    }
+}
+
+// request sends the request to the running whichkey and returns its answer
+pub fn request(path: &Path, request: &Request) -> Result<Value, Error> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|err| Error::new(err.kind(), format!("whichkey is not running: {}", err)))?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn mapping_json(mapping: &config::Mapping) -> Value {
+    let kind = match mapping.action {
+        config::Action::Application { .. } => "Application",
+        config::Action::Command { .. } => "Command",
+    };
+
+    json!({
+        "keys": mapping.keys,
+        "kind": kind,
+        "command": mapping.action.command(),
+        "description": mapping.label(),
+    })
+}
+
+fn binding_json(group: &str, mapping: &config::Mapping) -> Value {
+    let mut binding = mapping_json(mapping);
+    binding["group"] = json!(group);
+    binding
+}
+
+fn health_json(stats: &health::Stats) -> Value {
+    json!({
+        "callbacks": stats.callbacks,
+        "slow_callbacks": stats.slow_callbacks,
+        "average_latency_us": stats.average_latency.as_micros() as u64,
+        "max_latency_us": stats.max_latency.as_micros() as u64,
+        "disabled": {
+            "timeout": stats.disabled_by_timeout,
+            "user_input": stats.disabled_by_user_input,
+            "unnoticed": stats.disabled_unnoticed,
+        },
+        "backoff_ms": stats.backoff.as_millis() as u64,
+    })
+}
+
+fn event_json(event: &Event) -> Value {
+    match event {
+        Event::Fired(mapping) => json!({"event": "fired", "mapping": mapping_json(mapping)}),
+        Event::Aborted(keys) => json!({"event": "aborted", "keys": keys}),
+        Event::Reloaded => json!({"event": "reloaded"}),
+        Event::Paused => json!({"event": "paused"}),
+        Event::Resumed => json!({"event": "resumed"}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use crate::input::{KeyState, Modifiers};
+
+    const CONFIG: &str = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+"#;
+
+    // start serves a recorder of the config on a socket of its own, and
+    // returns its path and the recorder
+    fn start(name: &str) -> (PathBuf, SharedRecorder) {
+        let path =
+            std::env::temp_dir().join(format!("whichkey-{}-{}.sock", name, std::process::id()));
+        let config_path = path.with_extension("toml");
+        fs::write(&config_path, CONFIG.replace("Finder", "Safari")).unwrap();
+
+        let (sender, events) = mpsc::channel();
+        let recorder = KeyStrokeRecorder::with_config(toml::from_str(CONFIG).unwrap())
+            .on_fire(|_| {})
+            .with_events(sender);
+        let recorder = SharedRecorder(Arc::new(Mutex::new(recorder)));
+
+        let listener = listen(&path).unwrap();
+        Server::new(recorder.clone(), config_path).serve(listener, events);
+        (path, recorder)
+    }
+
+    fn trigger(keys: &str, dry_run: bool) -> Request {
+        Request::Trigger {
+            keys: keys.to_string(),
+            dry_run,
+        }
+    }
+
+    fn key(name: &str, state: KeyState) -> KeyEvent {
+        KeyEvent {
+            key: name.to_string(),
+            modifiers: Modifiers::default(),
+            state,
+            timestamp: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn requests_are_answered() {
+        let (path, _) = start("requests");
+
+        let status = request(&path, &Request::Status).unwrap();
+        assert_eq!(status["ok"], true);
+        assert_eq!(status["paused"], false);
+        assert_eq!(status["leader_key"], "option");
+
+        let bindings = request(&path, &Request::ListBindings).unwrap();
+        assert_eq!(
+            bindings["bindings"],
+            json!([{
+                "keys": "of",
+                "kind": "Application",
+                "command": "Finder",
+                "description": "Finder",
+                "group": "Open Applications",
+            }])
+        );
+
+        let resolved = request(&path, &trigger("of", true)).unwrap();
+        assert_eq!(resolved["mapping"]["command"], "Finder");
+        assert!(resolved.get("fired").is_none());
+        let fired = request(&path, &trigger("of", false)).unwrap();
+        assert_eq!(fired["fired"]["command"], "Finder");
+        let failed = request(&path, &trigger("o", false)).unwrap();
+        assert_eq!(failed["ok"], false);
+
+        assert_eq!(request(&path, &Request::Reload).unwrap()["ok"], true);
+        let fired = request(&path, &trigger("of", false)).unwrap();
+        assert_eq!(fired["fired"]["command"], "Safari");
+
+        fs::remove_file(path.with_extension("toml")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pause_lets_keys_through() {
+        let (path, mut recorder) = start("pause");
+
+        assert_eq!(request(&path, &Request::Pause).unwrap()["ok"], true);
+        assert_eq!(request(&path, &Request::Status).unwrap()["paused"], true);
+        for event in [
+            key("option", KeyState::Down),
+            key("option", KeyState::Up),
+            key("o", KeyState::Down),
+        ] {
+            assert_eq!(recorder.handle(&event), Verdict::Pass);
+        }
+
+        request(&path, &Request::Resume).unwrap();
+        recorder.handle(&key("option", KeyState::Down));
+        recorder.handle(&key("option", KeyState::Up));
+        assert_eq!(recorder.handle(&key("o", KeyState::Down)), Verdict::Consume);
+
+        fs::remove_file(path.with_extension("toml")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn subscribers_receive_events() {
+        let (path, _) = start("events");
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        writeln!(stream, "{{\"command\": \"subscribe-events\"}}").unwrap();
+        writeln!(stream, "not json").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true}"#);
+        let invalid: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(invalid["ok"], false);
+
+        request(&path, &Request::Pause).unwrap();
+        request(&path, &trigger("of", false)).unwrap();
+        let events: Vec<Value> = (0..2)
+            .map(|_| serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap())
+            .collect();
+        assert_eq!(events[0], json!({"event": "paused"}));
+        assert_eq!(events[1]["event"], "fired");
+        assert_eq!(events[1]["mapping"]["keys"], "of");
+
+        fs::remove_file(path.with_extension("toml")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn subscriber_that_stops_reading_is_dropped() {
+        let path =
+            std::env::temp_dir().join(format!("whichkey-stalled-{}.sock", std::process::id()));
+        let recorder =
+            KeyStrokeRecorder::with_config(toml::from_str(CONFIG).unwrap()).on_fire(|_| {});
+        let server = Server::new(
+            SharedRecorder(Arc::new(Mutex::new(recorder))),
+            path.with_extension("toml"),
+        );
+        let subscribers = server.subscribers.clone();
+        let (sender, events) = mpsc::channel();
+        server.serve(listen(&path).unwrap(), events);
+
+        let subscribe = || {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            writeln!(stream, "{{\"command\": \"subscribe-events\"}}").unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true}"#);
+            lines
+        };
+        let _stalled = subscribe();
+        let reading = subscribe();
+        while subscribers.lock().unwrap().len() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Far more events than the socket holds, the other subscriber
+        // still gets them all
+        let read = thread::spawn(move || {
+            reading
+                .map(Result::unwrap)
+                .position(|line| line == r#"{"event":"resumed"}"#)
+        });
+        for _ in 0..100_000 {
+            sender.send(Event::Paused).unwrap();
+        }
+        sender.send(Event::Resumed).unwrap();
+        assert_eq!(read.join().unwrap(), Some(100_000));
+        // The subscribers are back in the list once the event is sent
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while subscribers.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(subscribers.lock().unwrap().len(), 1);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn listen_refuses_running_socket_and_replaces_stale_one() {
+        let (path, _) = start("listen");
+
+        let err = listen(&path).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(path.with_extension("toml")).unwrap();
+        fs::remove_file(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(listen(&path).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,687 @@
+use evdev::uinput::VirtualDevice;
+use evdev::{AttributeSet, Device, EventSummary, EventType, InputEvent, KeyCode};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::os::fd::AsFd;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::input::{InputBackend, KeyEvent, KeyEventHandler, KeyState, Modifiers, Verdict};
+use crate::layout::{localize, Layout};
+
+// Name of the uinput keyboard the key events are sent on through, it's
+// never grabbed
+pub const VIRTUAL_KEYBOARD_NAME: &str = "whichkey virtual keyboard";
+
+// How often /dev/input is scanned for keyboards plugged in
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_TIMEOUT_MS: u16 = 200;
+
+// evdev key values
+const KEY_UP: i32 = 0;
+const KEY_DOWN: i32 = 1;
+const KEY_REPEAT: i32 = 2;
+
+// Set by SIGINT and SIGTERM, so the keyboards get ungrabbed before exiting
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+// EvdevBackend grabs the keyboards through evdev, so no other program sees
+// their key events, and sends the ones the handler lets through on to a
+// uinput virtual keyboard. It needs read access to /dev/input and write
+// access to /dev/uinput, e.g. by being in the input group.
+pub struct EvdevBackend {
+    // Paths or names of the keyboards to grab, all keyboards when empty
+    devices: Vec<String>,
+    // Names the keys after the characters they type, when keys aren't
+    // named by their position
+    layout: Option<Layout>,
+    stop: Arc<AtomicBool>,
+}
+
+struct GrabbedDevice {
+    path: PathBuf,
+    device: Device,
+}
+
+impl EvdevBackend {
+    pub fn new(devices: Vec<String>, layout: Option<Layout>) -> Self {
+        EvdevBackend {
+            devices,
+            layout,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) || SIGNALLED.load(Ordering::SeqCst)
+    }
+
+    // is_selected tells if the device is one the user asked for, or any
+    // keyboard when they didn't ask for any
+    fn is_selected(&self, path: &Path, device: &Device) -> bool {
+        if device.name() == Some(VIRTUAL_KEYBOARD_NAME) {
+            return false;
+        }
+
+        if self.devices.is_empty() {
+            return is_keyboard(device);
+        }
+
+        let canonical_path = path.canonicalize().ok();
+        self.devices.iter().any(|selected| {
+            device.name() == Some(selected.as_str())
+                || Path::new(selected).canonicalize().ok() == canonical_path
+        })
+    }
+
+    // scan grabs the selected devices that aren't grabbed yet
+    fn scan(&self, grabbed: &mut Vec<GrabbedDevice>) {
+        for (path, mut device) in evdev::enumerate() {
+            if grabbed.iter().any(|g| g.path == path) || !self.is_selected(&path, &device) {
+                continue;
+            }
+
+            // Grabbing a keyboard while a key is held would leave the key
+            // stuck, its key up would never reach the other programs
+            match device.get_key_state() {
+                Ok(keys) if keys.iter().next().is_some() => continue,
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Failed to read key state of {}: {}", path.display(), err);
+                    continue;
+                }
+            }
+
+            if let Err(err) = device.set_nonblocking(true).and_then(|_| device.grab()) {
+                log::warn!("Failed to grab {}: {}", path.display(), err);
+                continue;
+            }
+
+            log::info!(
+                "Grabbed keyboard {} at {}",
+                device.name().unwrap_or("unnamed"),
+                path.display()
+            );
+            grabbed.push(GrabbedDevice { path, device });
+        }
+    }
+}
+
+impl InputBackend for EvdevBackend {
+    fn run(&mut self, handler: Box<dyn KeyEventHandler>) -> Result<(), Error> {
+        let mut handler = localize(handler, self.layout.clone());
+        let on_signal = SigAction::new(
+            SigHandler::Handler(on_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        for signal in [Signal::SIGINT, Signal::SIGTERM] {
+            unsafe { sigaction(signal, &on_signal) }?;
+        }
+
+        let mut output = virtual_keyboard()?;
+        let mut grabbed: Vec<GrabbedDevice> = vec![];
+        let mut router = Router::default();
+        let mut last_scan: Option<Instant> = None;
+
+        log::info!("Monitoring input events via evdev.");
+        // Failing, the keyboards are released all the same before the error
+        // is returned
+        let result = 'running: loop {
+            if self.stopped() {
+                break Ok(());
+            }
+            if last_scan.is_none_or(|scanned_at| scanned_at.elapsed() >= RESCAN_INTERVAL) {
+                self.scan(&mut grabbed);
+                last_scan = Some(Instant::now());
+            }
+
+            let mut fds: Vec<PollFd> = grabbed
+                .iter()
+                .map(|g| PollFd::new(g.device.as_fd(), PollFlags::POLLIN))
+                .collect();
+            match poll(&mut fds, PollTimeout::from(POLL_TIMEOUT_MS)) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(err) => {
+                    log::error!("Failed to wait for the keyboards: {}", err);
+                    break 'running Err(err.into());
+                }
+            }
+            let ready: Vec<usize> = fds
+                .iter()
+                .enumerate()
+                .filter(|(_, fd)| fd.revents().is_some_and(|revents| !revents.is_empty()))
+                .map(|(i, _)| i)
+                .collect();
+            drop(fds);
+
+            let mut unplugged = vec![];
+            for i in ready {
+                let fetched = grabbed[i]
+                    .device
+                    .fetch_events()
+                    .map(|events| events.collect::<Vec<InputEvent>>());
+                let events = match fetched {
+                    Ok(events) => events,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                    Err(err) => {
+                        log::info!("Keyboard {} is gone: {}", grabbed[i].path.display(), err);
+                        unplugged.push(i);
+                        continue;
+                    }
+                };
+
+                for event in events {
+                    if let EventSummary::Key(_, code, value) = event.destructure() {
+                        let routed = router.route(code, value, handler.as_mut());
+                        if routed.is_empty() {
+                            continue;
+                        }
+                        if let Err(err) = output.emit(&routed) {
+                            log::error!("Failed to send keys to the virtual keyboard: {}", err);
+                            break 'running Err(err);
+                        }
+                    }
+                }
+            }
+
+            for i in unplugged.into_iter().rev() {
+                grabbed.remove(i);
+            }
+        };
+
+        for mut g in grabbed {
+            if let Err(err) = g.device.ungrab() {
+                log::warn!("Failed to ungrab {}: {}", g.path.display(), err);
+            }
+        }
+        log::info!("Released keyboards. Exiting...");
+
+        result
+    }
+}
+
+extern "C" fn on_signal(_: c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+// Router hands the key events to the handler and decides which ones go on
+// to the virtual keyboard. A key whose key down was swallowed has its
+// repeats and key up swallowed as well.
+#[derive(Default)]
+struct Router {
+    held: HashSet<KeyCode>,
+    consumed: HashSet<KeyCode>,
+}
+
+impl Router {
+    fn route(
+        &mut self,
+        code: KeyCode,
+        value: i32,
+        handler: &mut dyn KeyEventHandler,
+    ) -> Vec<InputEvent> {
+        let pass = vec![key_event(code, value)];
+        match value {
+            KEY_DOWN => self.held.insert(code),
+            KEY_UP => self.held.remove(&code),
+            KEY_REPEAT if self.consumed.contains(&code) => return vec![],
+            _ => return pass,
+        };
+
+        // The handler still sees the key up, a leader key that isn't a
+        // modifier is only typed once it's released
+        let consumed = value == KEY_UP && self.consumed.remove(&code);
+        let pass = if consumed { vec![] } else { pass };
+
+        let Some(name) = key_code_to_name(code) else {
+            return pass;
+        };
+
+        let verdict = handler.handle(&KeyEvent {
+            key: name.to_string(),
+            modifiers: self.modifiers(),
+            state: if value == KEY_DOWN {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            },
+            timestamp: Instant::now(),
+        });
+
+        match verdict {
+            Verdict::Pass => pass,
+            Verdict::Consume => {
+                if value == KEY_DOWN {
+                    self.consumed.insert(code);
+                }
+                vec![]
+            }
+            Verdict::Replay(events) => {
+                if value == KEY_DOWN {
+                    self.consumed.insert(code);
+                }
+                events
+                    .iter()
+                    .filter_map(|event| name_to_key_code(&event.key))
+                    .flat_map(|code| [key_event(code, KEY_DOWN), key_event(code, KEY_UP)])
+                    .collect()
+            }
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let held = |codes: [KeyCode; 2]| codes.iter().any(|code| self.held.contains(code));
+        Modifiers {
+            shift: held([KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT]),
+            control: held([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL]),
+            option: held([KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT]),
+            command: held([KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA]),
+        }
+    }
+}
+
+fn key_event(code: KeyCode, value: i32) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, code.code(), value)
+}
+
+// is_keyboard tells keyboards apart from the other devices with keys, like
+// power buttons or mice
+fn is_keyboard(device: &Device) -> bool {
+    device.supported_keys().is_some_and(|keys| {
+        keys.contains(KeyCode::KEY_A)
+            && keys.contains(KeyCode::KEY_Z)
+            && keys.contains(KeyCode::KEY_ENTER)
+    })
+}
+
+fn virtual_keyboard() -> Result<VirtualDevice, Error> {
+    let mut keys = AttributeSet::<KeyCode>::new();
+    for (code, _) in KEY_NAMES {
+        keys.insert(*code);
+    }
+    // Keys whichkey has no name for still need to go through
+    for code in 1..=KeyCode::KEY_MICMUTE.code() {
+        keys.insert(KeyCode::new(code));
+    }
+
+    VirtualDevice::builder()?
+        .name(VIRTUAL_KEYBOARD_NAME)
+        .with_keys(&keys)?
+        .build()
+}
+
+// Linux key codes, named after the keys of the Mac keyboard they sit at:
+// alt is option and the super key is command
+const KEY_NAMES: &[(KeyCode, &str)] = &[
+    (KeyCode::KEY_A, "a"),
+    (KeyCode::KEY_B, "b"),
+    (KeyCode::KEY_C, "c"),
+    (KeyCode::KEY_D, "d"),
+    (KeyCode::KEY_E, "e"),
+    (KeyCode::KEY_F, "f"),
+    (KeyCode::KEY_G, "g"),
+    (KeyCode::KEY_H, "h"),
+    (KeyCode::KEY_I, "i"),
+    (KeyCode::KEY_J, "j"),
+    (KeyCode::KEY_K, "k"),
+    (KeyCode::KEY_L, "l"),
+    (KeyCode::KEY_M, "m"),
+    (KeyCode::KEY_N, "n"),
+    (KeyCode::KEY_O, "o"),
+    (KeyCode::KEY_P, "p"),
+    (KeyCode::KEY_Q, "q"),
+    (KeyCode::KEY_R, "r"),
+    (KeyCode::KEY_S, "s"),
+    (KeyCode::KEY_T, "t"),
+    (KeyCode::KEY_U, "u"),
+    (KeyCode::KEY_V, "v"),
+    (KeyCode::KEY_W, "w"),
+    (KeyCode::KEY_X, "x"),
+    (KeyCode::KEY_Y, "y"),
+    (KeyCode::KEY_Z, "z"),
+    (KeyCode::KEY_1, "1"),
+    (KeyCode::KEY_2, "2"),
+    (KeyCode::KEY_3, "3"),
+    (KeyCode::KEY_4, "4"),
+    (KeyCode::KEY_5, "5"),
+    (KeyCode::KEY_6, "6"),
+    (KeyCode::KEY_7, "7"),
+    (KeyCode::KEY_8, "8"),
+    (KeyCode::KEY_9, "9"),
+    (KeyCode::KEY_0, "0"),
+    (KeyCode::KEY_MINUS, "-"),
+    (KeyCode::KEY_EQUAL, "="),
+    (KeyCode::KEY_LEFTBRACE, "["),
+    (KeyCode::KEY_RIGHTBRACE, "]"),
+    (KeyCode::KEY_SEMICOLON, ";"),
+    (KeyCode::KEY_APOSTROPHE, "'"),
+    (KeyCode::KEY_GRAVE, "`"),
+    (KeyCode::KEY_BACKSLASH, "\\"),
+    (KeyCode::KEY_COMMA, ","),
+    (KeyCode::KEY_DOT, "."),
+    (KeyCode::KEY_SLASH, "/"),
+    (KeyCode::KEY_ENTER, "return"),
+    (KeyCode::KEY_TAB, "tab"),
+    (KeyCode::KEY_SPACE, "space"),
+    (KeyCode::KEY_BACKSPACE, "delete"),
+    (KeyCode::KEY_ESC, "escape"),
+    (KeyCode::KEY_LEFTMETA, "command"),
+    (KeyCode::KEY_RIGHTMETA, "rightcommand"),
+    (KeyCode::KEY_LEFTSHIFT, "shift"),
+    (KeyCode::KEY_RIGHTSHIFT, "rightshift"),
+    (KeyCode::KEY_CAPSLOCK, "capslock"),
+    (KeyCode::KEY_LEFTALT, "option"),
+    (KeyCode::KEY_RIGHTALT, "rightoption"),
+    (KeyCode::KEY_LEFTCTRL, "control"),
+    (KeyCode::KEY_RIGHTCTRL, "rightcontrol"),
+    (KeyCode::KEY_FN, "fn"),
+    (KeyCode::KEY_F1, "f1"),
+    (KeyCode::KEY_F2, "f2"),
+    (KeyCode::KEY_F3, "f3"),
+    (KeyCode::KEY_F4, "f4"),
+    (KeyCode::KEY_F5, "f5"),
+    (KeyCode::KEY_F6, "f6"),
+    (KeyCode::KEY_F7, "f7"),
+    (KeyCode::KEY_F8, "f8"),
+    (KeyCode::KEY_F9, "f9"),
+    (KeyCode::KEY_F10, "f10"),
+    (KeyCode::KEY_F11, "f11"),
+    (KeyCode::KEY_F12, "f12"),
+    (KeyCode::KEY_F13, "f13"),
+    (KeyCode::KEY_F14, "f14"),
+    (KeyCode::KEY_F15, "f15"),
+    (KeyCode::KEY_F16, "f16"),
+    (KeyCode::KEY_F17, "f17"),
+    (KeyCode::KEY_F18, "f18"),
+    (KeyCode::KEY_F19, "f19"),
+    (KeyCode::KEY_F20, "f20"),
+    (KeyCode::KEY_KPDOT, "keypad."),
+    (KeyCode::KEY_KPASTERISK, "keypad*"),
+    (KeyCode::KEY_KPPLUS, "keypad+"),
+    (KeyCode::KEY_NUMLOCK, "keypadclear"),
+    (KeyCode::KEY_KPSLASH, "keypad/"),
+    (KeyCode::KEY_KPENTER, "keypadenter"),
+    (KeyCode::KEY_KPMINUS, "keypad-"),
+    (KeyCode::KEY_KPEQUAL, "keypad="),
+    (KeyCode::KEY_KP0, "keypad0"),
+    (KeyCode::KEY_KP1, "keypad1"),
+    (KeyCode::KEY_KP2, "keypad2"),
+    (KeyCode::KEY_KP3, "keypad3"),
+    (KeyCode::KEY_KP4, "keypad4"),
+    (KeyCode::KEY_KP5, "keypad5"),
+    (KeyCode::KEY_KP6, "keypad6"),
+    (KeyCode::KEY_KP7, "keypad7"),
+    (KeyCode::KEY_KP8, "keypad8"),
+    (KeyCode::KEY_KP9, "keypad9"),
+    (KeyCode::KEY_HELP, "help"),
+    (KeyCode::KEY_INSERT, "help"),
+    (KeyCode::KEY_HOME, "home"),
+    (KeyCode::KEY_PAGEUP, "pageup"),
+    (KeyCode::KEY_DELETE, "forwarddelete"),
+    (KeyCode::KEY_END, "end"),
+    (KeyCode::KEY_PAGEDOWN, "pagedown"),
+    (KeyCode::KEY_LEFT, "left"),
+    (KeyCode::KEY_RIGHT, "right"),
+    (KeyCode::KEY_DOWN, "down"),
+    (KeyCode::KEY_UP, "up"),
+];
+
+// key_names returns the evdev codes of the keys whichkey names, with their
+// names
+pub fn key_names() -> impl Iterator<Item = (u16, &'static str)> {
+    KEY_NAMES.iter().map(|(code, name)| (code.code(), *name))
+}
+
+fn key_code_to_name(code: KeyCode) -> Option<&'static str> {
+    KEY_NAMES
+        .iter()
+        .find(|(key_code, _)| *key_code == code)
+        .map(|(_, name)| *name)
+}
+
+fn name_to_key_code(name: &str) -> Option<KeyCode> {
+    KEY_NAMES
+        .iter()
+        .find(|(_, key_name)| *key_name == name)
+        .map(|(key_code, _)| *key_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequence::KeyStrokeRecorder;
+
+    const CONFIG: &str = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "rt"
+  kind = "Command"
+  command = "true"
+"#;
+
+    // recorder is the recorder of the config, its mappings don't run
+    // anything
+    fn recorder() -> KeyStrokeRecorder {
+        KeyStrokeRecorder::with_config(toml::from_str(CONFIG).unwrap()).on_fire(|_| {})
+    }
+
+    // type_keys routes a key down and up for each key, as a keyboard would
+    // send them, and returns what reaches the virtual keyboard
+    fn type_keys(
+        router: &mut Router,
+        recorder: &mut KeyStrokeRecorder,
+        codes: &[KeyCode],
+    ) -> Vec<(KeyCode, i32)> {
+        codes
+            .iter()
+            .flat_map(|code| [(*code, KEY_DOWN), (*code, KEY_UP)])
+            .flat_map(|(code, value)| router.route(code, value, recorder))
+            .map(|event| (KeyCode::new(event.code()), event.value()))
+            .collect()
+    }
+
+    // Seen lets every key through and keeps their modifiers
+    #[derive(Default)]
+    struct Seen(Vec<Modifiers>);
+
+    impl KeyEventHandler for Seen {
+        fn handle(&mut self, event: &KeyEvent) -> Verdict {
+            self.0.push(event.modifiers);
+            Verdict::Pass
+        }
+
+        fn is_pending(&self) -> bool {
+            false
+        }
+    }
+
+    fn pressed(codes: &[KeyCode]) -> Vec<(KeyCode, i32)> {
+        codes
+            .iter()
+            .flat_map(|code| [(*code, KEY_DOWN), (*code, KEY_UP)])
+            .collect()
+    }
+
+    #[test]
+    fn route_passes_typing_through() {
+        let mut router = Router::default();
+        let mut recorder = recorder();
+
+        let keys = [KeyCode::KEY_R, KeyCode::KEY_T, KeyCode::KEY_LEFTALT];
+        assert_eq!(type_keys(&mut router, &mut recorder, &keys), pressed(&keys));
+    }
+
+    #[test]
+    fn route_swallows_sequence_with_its_key_ups() {
+        let mut router = Router::default();
+        let mut recorder = recorder();
+
+        let routed = type_keys(
+            &mut router,
+            &mut recorder,
+            &[
+                KeyCode::KEY_LEFTALT,
+                KeyCode::KEY_R,
+                KeyCode::KEY_T,
+                KeyCode::KEY_A,
+            ],
+        );
+
+        assert_eq!(routed, pressed(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_A]));
+        assert!(router.consumed.is_empty());
+    }
+
+    #[test]
+    fn route_replays_aborted_sequence() {
+        let mut router = Router::default();
+        let mut recorder = recorder();
+
+        let routed = type_keys(
+            &mut router,
+            &mut recorder,
+            &[KeyCode::KEY_LEFTALT, KeyCode::KEY_R, KeyCode::KEY_X],
+        );
+
+        assert_eq!(
+            routed,
+            pressed(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_R, KeyCode::KEY_X])
+        );
+    }
+
+    #[test]
+    fn route_swallows_repeats_of_swallowed_key() {
+        let mut router = Router::default();
+        let mut recorder = recorder();
+
+        for (code, value) in [
+            (KeyCode::KEY_LEFTALT, KEY_DOWN),
+            (KeyCode::KEY_LEFTALT, KEY_UP),
+        ] {
+            router.route(code, value, &mut recorder);
+        }
+
+        assert!(router
+            .route(KeyCode::KEY_R, KEY_DOWN, &mut recorder)
+            .is_empty());
+        assert!(router
+            .route(KeyCode::KEY_R, KEY_REPEAT, &mut recorder)
+            .is_empty());
+        assert!(router
+            .route(KeyCode::KEY_R, KEY_UP, &mut recorder)
+            .is_empty());
+    }
+
+    #[test]
+    fn route_hands_key_up_of_swallowed_leader_to_handler() {
+        let mut router = Router::default();
+        let mut recorder = KeyStrokeRecorder::with_config(
+            toml::from_str(&CONFIG.replace("option", "f18")).unwrap(),
+        )
+        .on_fire(|_| {});
+
+        let routed = type_keys(
+            &mut router,
+            &mut recorder,
+            &[KeyCode::KEY_F18, KeyCode::KEY_R, KeyCode::KEY_T],
+        );
+
+        assert!(routed.is_empty());
+        assert!(router.consumed.is_empty());
+    }
+
+    #[test]
+    fn route_reports_held_modifiers() {
+        let mut router = Router::default();
+        let mut handler = Seen::default();
+
+        router.route(KeyCode::KEY_RIGHTSHIFT, KEY_DOWN, &mut handler);
+        router.route(KeyCode::KEY_A, KEY_DOWN, &mut handler);
+
+        assert!(handler.0[1].shift);
+        assert!(!handler.0[1].control);
+    }
+
+    // Runs the backend against a uinput keyboard, e.g. with
+    // `sudo -E cargo test -- --ignored evdev`
+    #[test]
+    #[ignore = "needs read access to /dev/input and write access to /dev/uinput"]
+    fn evdev_backend_grabs_uinput_keyboard() {
+        use std::thread;
+
+        let mut keys = AttributeSet::<KeyCode>::new();
+        for (code, _) in KEY_NAMES {
+            keys.insert(*code);
+        }
+        let mut keyboard = VirtualDevice::builder()
+            .unwrap()
+            .name("whichkey test keyboard")
+            .with_keys(&keys)
+            .unwrap()
+            .build()
+            .unwrap();
+        let path = keyboard
+            .enumerate_dev_nodes_blocking()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+
+        let mut backend = EvdevBackend::new(vec![path.display().to_string()], None);
+        let stop = backend.stop.clone();
+        let running = thread::spawn(move || backend.run(Box::new(recorder())));
+
+        // Wait for the backend to create its virtual keyboard and grab ours
+        let mut output = None;
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(100));
+            output = evdev::enumerate()
+                .map(|(_, device)| device)
+                .find(|device| device.name() == Some(VIRTUAL_KEYBOARD_NAME));
+            if output.is_some() {
+                break;
+            }
+        }
+        let mut output = output.expect("virtual keyboard not created");
+        output.set_nonblocking(true).unwrap();
+        thread::sleep(RESCAN_INTERVAL);
+
+        for code in [
+            KeyCode::KEY_LEFTALT,
+            KeyCode::KEY_R,
+            KeyCode::KEY_T,
+            KeyCode::KEY_A,
+        ] {
+            keyboard.emit(&[key_event(code, KEY_DOWN)]).unwrap();
+            keyboard.emit(&[key_event(code, KEY_UP)]).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+
+        let routed: Vec<(KeyCode, i32)> = output
+            .fetch_events()
+            .unwrap()
+            .filter_map(|event| match event.destructure() {
+                EventSummary::Key(_, code, value) => Some((code, value)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(routed, pressed(&[KeyCode::KEY_LEFTALT, KeyCode::KEY_A]));
+
+        stop.store(true, Ordering::SeqCst);
+        running.join().unwrap().unwrap();
+
+        // The keyboard is released on exit, so it can be grabbed again
+        let mut device = Device::open(&path).unwrap();
+        device.grab().unwrap();
+    }
+}
@@ -1,12 +1,24 @@
-use core_foundation::base::{CFAllocatorRef, CFIndex};
-use core_foundation::runloop::CFRunLoopSourceRef;
-use once_cell::sync::Lazy;
+use core_foundation::base::{CFAllocatorRef, CFIndex, CFRelease};
+use core_foundation::data::{CFDataGetBytePtr, CFDataRef};
+use core_foundation::runloop::{
+    CFRunLoopAddSource, CFRunLoopGetCurrent, CFRunLoopRun, CFRunLoopSourceRef,
+};
+use core_foundation::string::CFStringRef;
+use std::io::Error;
 use std::os::raw::{c_longlong, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 
-use crate::sequence::KeyStroke;
-use crate::sequence::KeyStrokeRecorder;
-
-pub use crate::accessibility::AXUIElementRef;
+use crate::accessibility::*;
+use crate::cf_utils::core_foundation_private::kCFRunLoopCommonModes;
+use crate::health::{Disable, HEALTH};
+use crate::input::{
+    InputBackend, KeyEvent, KeyEventHandler, KeyState, Modifiers, Verdict, MOUSE_CLICK,
+};
+use crate::layout::{localize, Layout};
+use crate::utils::open_accessibility_preferences;
 
 // Type Aliases & Structs for C Types
 pub type CGEventTapProxy = *mut c_void; // Opaque pointer
@@ -42,8 +54,11 @@ pub const K_CG_EVENT_NULL: CGEventType = 0; // Internal use
 
 // CGEventType Enum
 // https://learn.microsoft.com/en-us/dotnet/api/coregraphics.cgeventtype?view=xamarin-mac-sdk-14
-// pub const K_CG_EVENT_LEFT_MOUSE_DOWN: CGEventType = 1;
+pub const K_CG_EVENT_LEFT_MOUSE_DOWN: CGEventType = 1;
+pub const K_CG_EVENT_RIGHT_MOUSE_DOWN: CGEventType = 3;
+pub const K_CG_EVENT_OTHER_MOUSE_DOWN: CGEventType = 25;
 pub const K_CG_EVENT_KEY_DOWN: CGEventType = 10;
+pub const K_CG_EVENT_KEY_UP: CGEventType = 11;
 pub const K_CG_EVENT_NX_SYSDEFINED: CGEventType = 14;
 pub const K_CG_EVENT_FLAGS_CHANGED: CGEventType = 12;
 pub const K_CG_EVENT_TAP_DISABLED_BY_TIMEOUT: CGEventType = 0xFFFFFFFE;
@@ -52,9 +67,39 @@ pub const K_CG_EVENT_TAP_DISABLED_BY_USER_INPUT: CGEventType = 0xFFFFFFFF;
 // CGEventField
 // pub const K_CG_EVENT_TARGET_UNIX_PROCESS_ID: CGEventField = 8; // kCGEventTargetUnixProcessID
 pub const K_CG_KEYBOARD_EVENT_KEYCODE: CGEventField = 9; // kCGKeyboardEventKeycode
-pub const K_CG_EVENT_FLAG_MASK_OPTION_ALTERNATE: u64 = 524288;
-pub const K_CG_EVENT_FLAG_MASK_COMMAND_ALTERNATE: u64 = 1048840;
-pub const K_CG_EVENT_FLAG_MASK_CONTROL_ALTERNATE: u64 = 262401;
+pub const K_CG_EVENT_SOURCE_USER_DATA: CGEventField = 42; // kCGEventSourceUserData
+
+// CGEventFlags, the device independent bits
+// refer to https://docs.rs/objc2-core-graphics/latest/src/objc2_core_graphics/generated/CGEventTypes.rs.html#171
+pub const K_CG_EVENT_FLAG_MASK_SHIFT: u64 = 0x00020000;
+pub const K_CG_EVENT_FLAG_MASK_CONTROL: u64 = 0x00040000;
+pub const K_CG_EVENT_FLAG_MASK_ALTERNATE: u64 = 0x00080000;
+pub const K_CG_EVENT_FLAG_MASK_COMMAND: u64 = 0x00100000;
+pub const K_CG_EVENT_FLAG_MASK_ALPHA_SHIFT: u64 = 0x00010000;
+pub const K_CG_EVENT_FLAG_MASK_SECONDARY_FN: u64 = 0x00800000;
+pub const K_CG_EVENT_FLAG_MASK_NON_COALESCED: u64 = 0x00000100;
+
+// The device dependent bits telling the left and right modifiers apart,
+// refer to NX_DEVICE*KEYMASK of IOKit's IOLLEvent.h
+pub const NX_DEVICE_LCTL_KEY_MASK: u64 = 0x00000001;
+pub const NX_DEVICE_LSHIFT_KEY_MASK: u64 = 0x00000002;
+pub const NX_DEVICE_RSHIFT_KEY_MASK: u64 = 0x00000004;
+pub const NX_DEVICE_LCMD_KEY_MASK: u64 = 0x00000008;
+pub const NX_DEVICE_RCMD_KEY_MASK: u64 = 0x00000010;
+pub const NX_DEVICE_LALT_KEY_MASK: u64 = 0x00000020;
+pub const NX_DEVICE_RALT_KEY_MASK: u64 = 0x00000040;
+pub const NX_DEVICE_RCTL_KEY_MASK: u64 = 0x00002000;
+pub const NX_DEVICE_KEY_MASKS: u64 = NX_DEVICE_LCTL_KEY_MASK
+    | NX_DEVICE_LSHIFT_KEY_MASK
+    | NX_DEVICE_RSHIFT_KEY_MASK
+    | NX_DEVICE_LCMD_KEY_MASK
+    | NX_DEVICE_RCMD_KEY_MASK
+    | NX_DEVICE_LALT_KEY_MASK
+    | NX_DEVICE_RALT_KEY_MASK
+    | NX_DEVICE_RCTL_KEY_MASK;
+
+// Keycode of caps lock, its flags changed event comes once per press
+const CAPS_LOCK_KEYCODE: i64 = 57;
 
 #[link(name = "CoreGraphics", kind = "framework")]
 #[allow(non_snake_case)] // To allow function names like CGEventTapCreate
@@ -69,10 +114,20 @@ unsafe extern "C" {
     ) -> CFMachPortRef;
 
     pub fn CGEventTapEnable(tap: CFMachPortRef, enable: bool);
+    pub fn CGEventTapIsEnabled(tap: CFMachPortRef) -> bool;
 
     // pub fn CGEventGetLocation(event: CGEventRef) -> CGPoint;
     pub fn CGEventGetIntegerValueField(event: CGEventRef, field: CGEventField) -> c_longlong; // Note: Returns int64_t
     pub fn CGEventGetFlags(event: CGEventRef) -> u64;
+    pub fn CGEventSetFlags(event: CGEventRef, flags: u64);
+    pub fn CGEventSetIntegerValueField(event: CGEventRef, field: CGEventField, value: c_longlong);
+
+    pub fn CGEventCreateKeyboardEvent(
+        source: *mut c_void, // CGEventSourceRef, null for the default source
+        virtualKey: u16,
+        keyDown: bool,
+    ) -> CGEventRef;
+    pub fn CGEventPost(tap: CGEventTapLocation, event: CGEventRef);
 
     pub fn CFMachPortCreateRunLoopSource(
         allocator: CFAllocatorRef, // Usually kCFAllocatorDefault or null
@@ -81,7 +136,230 @@ unsafe extern "C" {
     ) -> CFRunLoopSourceRef;
 }
 
-static mut SEQUENCE_RECORDER: Lazy<KeyStrokeRecorder> = Lazy::new(|| KeyStrokeRecorder::new());
+pub type TISInputSourceRef = *mut c_void; // Opaque pointer
+pub type UniChar = u16;
+
+// UCKeyTranslate options, refer to Events.h and UnicodeUtilities.h of Carbon
+pub const K_UC_KEY_ACTION_DOWN: u16 = 0;
+// Dead keys type their accent instead of waiting for the next key
+pub const K_UC_KEY_TRANSLATE_NO_DEAD_KEYS_MASK: u32 = 1;
+// The shiftKey modifier, shifted right by 8 bits like UCKeyTranslate wants
+pub const K_UC_SHIFT_KEY_STATE: u32 = 0x0200 >> 8;
+
+#[link(name = "Carbon", kind = "framework")]
+#[allow(non_snake_case, non_upper_case_globals)]
+unsafe extern "C" {
+    pub static kTISPropertyUnicodeKeyLayoutData: CFStringRef;
+
+    pub fn TISCopyCurrentKeyboardLayoutInputSource() -> TISInputSourceRef;
+    pub fn TISGetInputSourceProperty(
+        inputSource: TISInputSourceRef,
+        propertyKey: CFStringRef,
+    ) -> *const c_void;
+    pub fn LMGetKbdType() -> u8;
+    pub fn UCKeyTranslate(
+        keyLayoutPtr: *const u8, // const UCKeyboardLayout *
+        virtualKeyCode: u16,
+        keyAction: u16,
+        modifierKeyState: u32,
+        keyboardType: u32,
+        keyTranslateOptions: u32,
+        deadKeyState: *mut u32,
+        maxStringLength: usize,
+        actualStringLength: *mut usize,
+        unicodeString: *mut UniChar,
+    ) -> i32; // OSStatus
+}
+
+// Marks the key events posted by replay_key_events, so the tap lets them
+// through instead of recording them again
+const REPLAYED_EVENT_MARKER: c_longlong = 0x77686b; // "whk"
+
+// The handler of the running backend, the event callback is a C function so
+// it can't capture it
+static HANDLER: Mutex<Option<Box<dyn KeyEventHandler>>> = Mutex::new(None);
+
+// The event tap, so it can be re-enabled once the system disables it
+static TAP: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+// Whether the raw keycodes of every key event are logged, see
+// config::LoggingConfig::debug_keys
+static DEBUG_KEYS: AtomicBool = AtomicBool::new(false);
+
+// EventTapBackend captures the key events of the login session with a
+// CGEventTap, it needs the Accessibility permissions
+pub struct EventTapBackend {
+    debug_keys: bool,
+    // Names the keys after the characters they type, when keys aren't
+    // named by their position
+    layout: Option<Layout>,
+}
+
+impl EventTapBackend {
+    pub fn new(debug_keys: bool, layout: Option<Layout>) -> Self {
+        EventTapBackend { debug_keys, layout }
+    }
+}
+
+// current_layout reads the characters the keys type with the keyboard
+// layout selected when whichkey starts, switching to another one needs a
+// restart
+pub fn current_layout() -> Result<Layout, Error> {
+    unsafe {
+        let source = TISCopyCurrentKeyboardLayoutInputSource();
+        if source.is_null() {
+            return Err(Error::other("No keyboard layout is selected"));
+        }
+
+        let data = TISGetInputSourceProperty(source, kTISPropertyUnicodeKeyLayoutData);
+        if data.is_null() {
+            CFRelease(source as *const c_void);
+            return Err(Error::other(
+                "The keyboard layout has no Unicode key layout",
+            ));
+        }
+
+        let key_layout = CFDataGetBytePtr(data as CFDataRef);
+        let keyboard_type = u32::from(LMGetKbdType());
+        let typed = |code: i64, modifiers: u32| {
+            let mut dead_key_state = 0;
+            let mut length = 0;
+            let mut chars: [UniChar; 4] = [0; 4];
+            let status = UCKeyTranslate(
+                key_layout,
+                code as u16,
+                K_UC_KEY_ACTION_DOWN,
+                modifiers,
+                keyboard_type,
+                K_UC_KEY_TRANSLATE_NO_DEAD_KEYS_MASK,
+                &mut dead_key_state,
+                chars.len(),
+                &mut length,
+                chars.as_mut_ptr(),
+            );
+            if status != 0 || length != 1 {
+                return None;
+            }
+            char::from_u32(u32::from(chars[0]))
+        };
+
+        let mut layout = Layout::default();
+        for (code, name) in KEY_NAMES
+            .iter()
+            .filter(|(_, name)| name.chars().count() == 1)
+        {
+            layout.insert(name, typed(*code, 0), typed(*code, K_UC_SHIFT_KEY_STATE));
+        }
+
+        CFRelease(source as *const c_void);
+        Ok(layout)
+    }
+}
+
+impl InputBackend for EventTapBackend {
+    fn run(&mut self, handler: Box<dyn KeyEventHandler>) -> Result<(), Error> {
+        *HANDLER.lock().unwrap() = Some(localize(handler, self.layout.clone()));
+        DEBUG_KEYS.store(self.debug_keys, Ordering::Relaxed);
+
+        unsafe {
+            // Check if accessibility is enabled using functions from accessibility module
+            let api_enabled = ax_api_enabled();
+            let process_trusted = ax_is_process_trusted();
+
+            log::debug!("Accessibility API status:");
+            log::debug!("- AXAPIEnabled: {}", api_enabled);
+            log::debug!("- AXIsProcessTrusted: {}", process_trusted);
+
+            if !api_enabled || !process_trusted {
+                log::warn!("Accessibility permissions may not be properly enabled.");
+                log::info!("Opening System Settings...");
+
+                println!("Accessibility permissions are required.");
+                println!(
+                    "Please add this app to System Settings → Privacy & Security → Accessibility"
+                );
+                println!("Opening System Settings now...");
+
+                open_accessibility_preferences();
+
+                println!("Please grant permissions and restart the app.");
+                log::warn!("App is waiting for permissions. Please restart after granting access.");
+                std::process::exit(0);
+            }
+
+            log::debug!("Attempting to create system-wide accessibility element...");
+            let system_wide = ax_ui_element_create_system_wide();
+            if system_wide.is_null() {
+                return Err(Error::other(
+                    "Failed to create system-wide accessibility element.",
+                ));
+            }
+            log::debug!("Created system-wide accessibility element");
+
+            log::debug!("Setting up CGEventTap for key events... ");
+            //
+            // Listens to both normal keys and modifier keys
+            let event_mask = (1 << K_CG_EVENT_KEY_DOWN)
+                | (1 << K_CG_EVENT_KEY_UP)
+                | (1 << K_CG_EVENT_FLAGS_CHANGED)
+                | (1 << K_CG_EVENT_NX_SYSDEFINED)
+                | (1 << K_CG_EVENT_LEFT_MOUSE_DOWN)
+                | (1 << K_CG_EVENT_RIGHT_MOUSE_DOWN)
+                | (1 << K_CG_EVENT_OTHER_MOUSE_DOWN);
+
+            let event_tap = CGEventTapCreate(
+                K_CG_SESSION_EVENT_TAP,
+                K_CG_HEAD_INSERT_EVENT_TAP,
+                K_CG_EVENT_TAP_DEFAULT,
+                event_mask,
+                event_callback,
+                system_wide as *mut c_void,
+            );
+
+            if event_tap.is_null() {
+                return Err(Error::other(
+                    "Failed to create CGEventTap. Make sure the process has Accessibility permissions.",
+                ));
+            }
+            log::debug!("CGEventTap created successfully.");
+
+            let run_loop_source = CFMachPortCreateRunLoopSource(ptr::null_mut(), event_tap, 0);
+            if run_loop_source.is_null() {
+                CFRelease(event_tap as *const c_void);
+                return Err(Error::other(
+                    "Failed to create RunLoop source for event tap.",
+                ));
+            }
+            log::debug!("RunLoop source created.");
+
+            let current_run_loop = CFRunLoopGetCurrent();
+            CFRunLoopAddSource(current_run_loop, run_loop_source, kCFRunLoopCommonModes);
+            log::debug!("Event tap source added to run loop.");
+
+            CGEventTapEnable(event_tap, true);
+            TAP.store(event_tap, Ordering::Relaxed);
+            HEALTH.enabled();
+            // Within the unsafe block, the closures can call them as is
+            HEALTH.watch(|| tap_enabled(), || enable_tap());
+            log::debug!("CGEventTap enabled.");
+
+            log::info!("Monitoring input events via CGEventTap.");
+            println!("Successfully running with accessibility permissions!");
+
+            log::info!("Starting main run loop...");
+            CFRunLoopRun();
+
+            log::info!("Run loop finished. Exiting...");
+
+            TAP.store(ptr::null_mut(), Ordering::Relaxed);
+            CGEventTapEnable(event_tap, false);
+            CFRelease(run_loop_source as *const c_void);
+            CFRelease(event_tap as *const c_void);
+        }
+
+        Ok(())
+    }
+}
 
 // The actual event callback function
 pub unsafe extern "C" fn event_callback(
@@ -95,43 +373,347 @@ pub unsafe extern "C" fn event_callback(
         return event; // Cannot proceed
     }
 
-    // Handle tap disable events
+    // The system disabled the tap, re-enable it right away, or let the
+    // watchdog do it once the backoff is over
     if typ == K_CG_EVENT_TAP_DISABLED_BY_TIMEOUT || typ == K_CG_EVENT_TAP_DISABLED_BY_USER_INPUT {
-        // println!("DEBUG: Event tap disabled (type: {})", typ);
-        // log::warn!(
-        //     "Event Tap disabled (type: {})! Input monitoring stopped.",
-        //     typ
-        // );
-        // We might need to re-enable the tap if desired.
-        // unsafe { CGEventTapEnable(proxy as CFMachPortRef, true) }; // Needs unsafe block if uncommented
+        let reason = if typ == K_CG_EVENT_TAP_DISABLED_BY_TIMEOUT {
+            Disable::Timeout
+        } else {
+            Disable::UserInput
+        };
+        if HEALTH.disabled(reason, Instant::now()).is_zero() {
+            unsafe { enable_tap() };
+        }
         return event; // Return the event directly
     }
 
-    if typ == K_CG_EVENT_KEY_DOWN || typ == K_CG_EVENT_FLAGS_CHANGED {
+    if unsafe { CGEventGetIntegerValueField(event, K_CG_EVENT_SOURCE_USER_DATA) }
+        == REPLAYED_EVENT_MARKER
+    {
+        return event;
+    }
+
+    // A click breaks a tap of the leader key, like option and click, and
+    // aborts a sequence under way. The click itself always goes through.
+    if typ == K_CG_EVENT_LEFT_MOUSE_DOWN
+        || typ == K_CG_EVENT_RIGHT_MOUSE_DOWN
+        || typ == K_CG_EVENT_OTHER_MOUSE_DOWN
+    {
+        let click = KeyEvent {
+            key: MOUSE_CLICK.to_string(),
+            modifiers: flags_to_modifiers(unsafe { CGEventGetFlags(event) }),
+            state: KeyState::Down,
+            timestamp: Instant::now(),
+        };
+        let verdict = match HANDLER.lock().unwrap().as_mut() {
+            Some(handler) => handler.handle(&click),
+            None => Verdict::Pass,
+        };
+        if let Verdict::Replay(events) = verdict {
+            unsafe { replay_key_events(&events) };
+        }
+        return event;
+    }
+
+    if typ == K_CG_EVENT_KEY_DOWN || typ == K_CG_EVENT_KEY_UP || typ == K_CG_EVENT_FLAGS_CHANGED {
         let keycode = unsafe { CGEventGetIntegerValueField(event, K_CG_KEYBOARD_EVENT_KEYCODE) };
         let flags = unsafe { CGEventGetFlags(event) };
 
-        log::info!(
-            "Key Down: KeyCode={}, Flags={}, Type: {}",
-            keycode,
-            flags,
-            typ,
-        );
-
-        SEQUENCE_RECORDER.record(KeyStroke {
-            key_code: keycode,
-            // key_typ: typ,
-            flag: flags,
-            // timestamp: Instant::now(),
-        });
-
-        if SEQUENCE_RECORDER.is_in_sequence() {
-            // println!("====in sequence");
-            SEQUENCE_RECORDER.check_sequence();
-
-            return std::ptr::null_mut();
+        if DEBUG_KEYS.load(Ordering::Relaxed) {
+            log::debug!(
+                "Key event: KeyCode={}, Flags={}, Type: {}",
+                keycode,
+                flags,
+                typ,
+            );
+        }
+
+        let key_event = |state: KeyState| KeyEvent {
+            key: key_code_to_name(keycode).to_string(),
+            modifiers: flags_to_modifiers(flags),
+            state,
+            timestamp: Instant::now(),
+        };
+
+        let verdict = match HANDLER.lock().unwrap().as_mut() {
+            // Caps lock toggles its flag on its way down only, every press
+            // is typed down and up
+            Some(handler) if typ == K_CG_EVENT_FLAGS_CHANGED && keycode == CAPS_LOCK_KEYCODE => {
+                match handler.handle(&key_event(KeyState::Down)) {
+                    Verdict::Pass => handler.handle(&key_event(KeyState::Up)),
+                    verdict => {
+                        handler.handle(&key_event(KeyState::Up));
+                        verdict
+                    }
+                }
+            }
+            Some(handler) => handler.handle(&key_event(key_state(typ, keycode, flags))),
+            None => Verdict::Pass,
+        };
+
+        match verdict {
+            Verdict::Pass => {}
+            Verdict::Consume => return ptr::null_mut(),
+            Verdict::Replay(events) => {
+                unsafe { replay_key_events(&events) };
+                return ptr::null_mut();
+            }
         }
     }
 
     event // Pass the event along
 }
+
+// tap_enabled tells if the event tap gets the keys, a tap that's gone
+// counts as enabled so the watchdog leaves it alone
+unsafe fn tap_enabled() -> bool {
+    let tap = TAP.load(Ordering::Relaxed);
+    tap.is_null() || unsafe { CGEventTapIsEnabled(tap) }
+}
+
+unsafe fn enable_tap() {
+    let tap = TAP.load(Ordering::Relaxed);
+    if tap.is_null() {
+        return;
+    }
+
+    unsafe { CGEventTapEnable(tap, true) };
+    HEALTH.enabled();
+    log::info!("Event tap re-enabled.");
+}
+
+// replay_key_events posts the key events swallowed by an aborted sequence,
+// so they reach the application in the order they were typed
+unsafe fn replay_key_events(events: &[KeyEvent]) {
+    for key_event in events {
+        let Some(key_code) = name_to_key_code(&key_event.key) else {
+            log::warn!("Can't replay unknown key {}", key_event.key);
+            continue;
+        };
+
+        for key_down in [true, false] {
+            let event =
+                unsafe { CGEventCreateKeyboardEvent(ptr::null_mut(), key_code as u16, key_down) };
+            if event.is_null() {
+                log::error!("Failed to create key event for replay");
+                return;
+            }
+
+            unsafe {
+                CGEventSetFlags(event, modifiers_to_flags(key_event.modifiers));
+                CGEventSetIntegerValueField(
+                    event,
+                    K_CG_EVENT_SOURCE_USER_DATA,
+                    REPLAYED_EVENT_MARKER,
+                );
+                CGEventPost(K_CG_SESSION_EVENT_TAP, event);
+                CFRelease(event as *const c_void);
+            }
+        }
+    }
+}
+
+// key_state tells if the key went down or up. Flags changed events are
+// down when the flag of the modifier is set, and its device dependent bit
+// tells the left one from the right one while the other one is held. Events
+// posted without those bits only have the flag to go by.
+fn key_state(typ: CGEventType, code: i64, flags: u64) -> KeyState {
+    match typ {
+        K_CG_EVENT_KEY_DOWN => return KeyState::Down,
+        K_CG_EVENT_KEY_UP => return KeyState::Up,
+        _ => {}
+    }
+
+    let (mask, side) = match code {
+        55 => (K_CG_EVENT_FLAG_MASK_COMMAND, NX_DEVICE_LCMD_KEY_MASK),
+        54 => (K_CG_EVENT_FLAG_MASK_COMMAND, NX_DEVICE_RCMD_KEY_MASK),
+        56 => (K_CG_EVENT_FLAG_MASK_SHIFT, NX_DEVICE_LSHIFT_KEY_MASK),
+        60 => (K_CG_EVENT_FLAG_MASK_SHIFT, NX_DEVICE_RSHIFT_KEY_MASK),
+        58 => (K_CG_EVENT_FLAG_MASK_ALTERNATE, NX_DEVICE_LALT_KEY_MASK),
+        61 => (K_CG_EVENT_FLAG_MASK_ALTERNATE, NX_DEVICE_RALT_KEY_MASK),
+        59 => (K_CG_EVENT_FLAG_MASK_CONTROL, NX_DEVICE_LCTL_KEY_MASK),
+        62 => (K_CG_EVENT_FLAG_MASK_CONTROL, NX_DEVICE_RCTL_KEY_MASK),
+        63 => (K_CG_EVENT_FLAG_MASK_SECONDARY_FN, 0),
+        CAPS_LOCK_KEYCODE => (K_CG_EVENT_FLAG_MASK_ALPHA_SHIFT, 0),
+        _ => return KeyState::Down,
+    };
+
+    let side_held = side == 0 || flags & NX_DEVICE_KEY_MASKS == 0 || flags & side > 0;
+    if flags & mask > 0 && side_held {
+        KeyState::Down
+    } else {
+        KeyState::Up
+    }
+}
+
+fn flags_to_modifiers(flags: u64) -> Modifiers {
+    Modifiers {
+        shift: flags & K_CG_EVENT_FLAG_MASK_SHIFT > 0,
+        control: flags & K_CG_EVENT_FLAG_MASK_CONTROL > 0,
+        option: flags & K_CG_EVENT_FLAG_MASK_ALTERNATE > 0,
+        command: flags & K_CG_EVENT_FLAG_MASK_COMMAND > 0,
+    }
+}
+
+fn modifiers_to_flags(modifiers: Modifiers) -> u64 {
+    let mut flags = K_CG_EVENT_FLAG_MASK_NON_COALESCED;
+    if modifiers.shift {
+        flags |= K_CG_EVENT_FLAG_MASK_SHIFT;
+    }
+    if modifiers.control {
+        flags |= K_CG_EVENT_FLAG_MASK_CONTROL;
+    }
+    if modifiers.option {
+        flags |= K_CG_EVENT_FLAG_MASK_ALTERNATE;
+    }
+    if modifiers.command {
+        flags |= K_CG_EVENT_FLAG_MASK_COMMAND;
+    }
+
+    flags
+}
+
+// key code mapping:
+// https://github.com/caseyscarborough/keylogger/blob/master/keylogger.c#L117
+// the modifiers held are in the flags, see keys::token
+const KEY_NAMES: &[(i64, &str)] = &[
+    (0, "a"),
+    (1, "s"),
+    (2, "d"),
+    (3, "f"),
+    (4, "h"),
+    (5, "g"),
+    (6, "z"),
+    (7, "x"),
+    (8, "c"),
+    (9, "v"),
+    (11, "b"),
+    (12, "q"),
+    (13, "w"),
+    (14, "e"),
+    (15, "r"),
+    (16, "y"),
+    (17, "t"),
+    (18, "1"),
+    (19, "2"),
+    (20, "3"),
+    (21, "4"),
+    (22, "6"),
+    (23, "5"),
+    (24, "="),
+    (25, "9"),
+    (26, "7"),
+    (27, "-"),
+    (28, "8"),
+    (29, "0"),
+    (30, "]"),
+    (31, "o"),
+    (32, "u"),
+    (33, "["),
+    (34, "i"),
+    (35, "p"),
+    (36, "return"),
+    (37, "l"),
+    (38, "j"),
+    (39, "'"),
+    (40, "k"),
+    (41, ";"),
+    (42, "\\"),
+    (43, ","),
+    (44, "/"),
+    (45, "n"),
+    (46, "m"),
+    (47, "."),
+    (48, "tab"),
+    (49, "space"),
+    (50, "`"),
+    (51, "delete"),
+    (53, "escape"),
+    (54, "rightcommand"),
+    (55, "command"),
+    (56, "shift"),
+    (57, "capslock"),
+    (58, "option"),
+    (59, "control"),
+    (60, "rightshift"),
+    (61, "rightoption"),
+    (62, "rightcontrol"),
+    (63, "fn"),
+    (64, "f17"),
+    (65, "keypad."),
+    (67, "keypad*"),
+    (69, "keypad+"),
+    (71, "keypadclear"),
+    (75, "keypad/"),
+    (76, "keypadenter"),
+    (78, "keypad-"),
+    (79, "f18"),
+    (80, "f19"),
+    (81, "keypad="),
+    (82, "keypad0"),
+    (83, "keypad1"),
+    (84, "keypad2"),
+    (85, "keypad3"),
+    (86, "keypad4"),
+    (87, "keypad5"),
+    (88, "keypad6"),
+    (89, "keypad7"),
+    (90, "f20"),
+    (91, "keypad8"),
+    (92, "keypad9"),
+    (96, "f5"),
+    (97, "f6"),
+    (98, "f7"),
+    (99, "f3"),
+    (100, "f8"),
+    (101, "f9"),
+    (103, "f11"),
+    (105, "f13"),
+    (106, "f16"),
+    (107, "f14"),
+    (109, "f10"),
+    (111, "f12"),
+    (113, "f15"),
+    (114, "help"),
+    (115, "home"),
+    (116, "pageup"),
+    (117, "forwarddelete"),
+    (118, "f4"),
+    (119, "end"),
+    (120, "f2"),
+    (121, "pagedown"),
+    (122, "f1"),
+    (123, "left"),
+    (124, "right"),
+    (125, "down"),
+    (126, "up"),
+];
+
+fn key_code_to_name(code: i64) -> &'static str {
+    KEY_NAMES
+        .iter()
+        .find(|(key_code, _)| *key_code == code)
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown")
+}
+
+fn name_to_key_code(name: &str) -> Option<i64> {
+    KEY_NAMES
+        .iter()
+        .find(|(_, key_name)| *key_name == name)
+        .map(|(key_code, _)| *key_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_modifier_has_a_key_code() {
+        for name in crate::keys::MODIFIER_KEYS {
+            let code = name_to_key_code(name);
+            assert!(code.is_some(), "{} has no key code", name);
+            assert_eq!(key_code_to_name(code.unwrap()), *name);
+        }
+    }
+}
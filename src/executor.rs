@@ -0,0 +1,213 @@
+use nix::libc::_exit;
+use nix::unistd::{execvp, fork, getpid, setsid, ForkResult};
+use std::ffi::CString;
+use std::io::Error;
+use std::process::{Child, Command};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+
+// Threads running the actions
+const WORKERS: usize = 2;
+
+// How often the children are checked for having exited
+const REAP_INTERVAL: Duration = Duration::from_millis(200);
+
+// Executor runs the actions of the mappings on its own threads, so the input
+// backend only matches keys and queues actions, and never waits for a
+// process to start. It keeps the processes it started until they exit, and
+// reaps them so they don't linger as zombies.
+#[derive(Clone)]
+pub struct Executor {
+    sender: Sender<config::Action>,
+    children: Arc<Mutex<Vec<Child>>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let children = Arc::new(Mutex::new(vec![]));
+
+        for _ in 0..WORKERS {
+            let receiver = receiver.clone();
+            let children = children.clone();
+            thread::spawn(move || work(receiver, children));
+        }
+
+        let reaped = Arc::downgrade(&children);
+        thread::spawn(move || {
+            // Reap until the executor and its workers are gone
+            while let Some(children) = reaped.upgrade() {
+                reap(&mut children.lock().unwrap());
+                drop(children);
+                thread::sleep(REAP_INTERVAL);
+            }
+        });
+
+        Executor { sender, children }
+    }
+
+    // submit queues the action, it never blocks
+    pub fn submit(&self, action: &config::Action) {
+        let _ = self.sender.send(action.clone());
+    }
+
+    // running counts the processes started that haven't exited yet
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn running(&self) -> usize {
+        let mut children = self.children.lock().unwrap();
+        reap(&mut children);
+        children.len()
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<config::Action>>>, children: Arc<Mutex<Vec<Child>>>) {
+    loop {
+        // The lock is only held while waiting, not while the action runs
+        let action = match receiver.lock().unwrap().recv() {
+            Ok(action) => action,
+            Err(_) => return,
+        };
+
+        if let Ok(child) = run(&action) {
+            children.lock().unwrap().push(child);
+        }
+    }
+}
+
+// reap forgets the children that exited, waiting for them so the system
+// can let them go
+fn reap(children: &mut Vec<Child>) {
+    children.retain_mut(|child| match child.try_wait() {
+        Ok(Some(status)) => {
+            log::debug!("Process {} exited: {}", child.id(), status);
+            false
+        }
+        Ok(None) => true,
+        Err(err) => {
+            log::error!("Failed to wait for process {}: {}", child.id(), err);
+            false
+        }
+    });
+}
+
+// run starts the process of the action and returns without waiting for it
+pub fn run(action: &config::Action) -> Result<Child, Error> {
+    match action {
+        config::Action::Application { command } => {
+            // fork_and_exec(command);
+            let cmd_result = Command::new("open").arg("-a").arg(command).spawn();
+
+            match &cmd_result {
+                Ok(child) => {
+                    log::info!("App launched successfully (pid: {}).", child.id());
+                }
+                Err(err) => {
+                    log::error!("Failed to open App : {}", err);
+                }
+            }
+            cmd_result
+        }
+        config::Action::Command { command } => {
+            let cmd_result = Command::new("sh").arg("-c").arg(command).spawn();
+
+            match &cmd_result {
+                Ok(child) => {
+                    log::info!("Command ran successfully (pid: {}).", child.id());
+                }
+                Err(err) => {
+                    log::error!("Failed to run command: {}", err);
+                }
+            }
+            cmd_result
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn fork_and_exec(command: &str) {
+    // First fork
+    match unsafe { fork() }.expect("First fork failed") {
+        ForkResult::Parent { child } => {
+            println!("Parent continuing, first child pid = {}", child);
+            // Parent returns immediately, doesn't exit
+            return;
+        }
+        ForkResult::Child => {
+            // First child continues
+        }
+    }
+
+    // Detach from terminal/session
+    setsid().expect("setsid failed");
+
+    // Second fork
+    match unsafe { fork() }.expect("Second fork failed") {
+        ForkResult::Parent { child } => {
+            // First child exits, leaving grandchild running
+            println!("First child exiting, grandchild pid = {}", child);
+            unsafe { _exit(0) }; // Use _exit instead of std::process::exit
+        }
+        ForkResult::Child => {
+            // Grandchild is now fully detached
+            println!("Daemon process running, pid = {}", getpid());
+            let cmd = CString::new("open").unwrap();
+            let arg1 = CString::new("-a").unwrap();
+            let arg2 = CString::new(command).unwrap();
+            let Err(err) = execvp(&cmd, &[cmd.clone(), arg1, arg2]);
+            panic!("execvp failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn command(command: &str) -> config::Action {
+        config::Action::Command {
+            command: command.to_string(),
+        }
+    }
+
+    // wait_for polls until the condition holds, for up to 5 seconds
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        false
+    }
+
+    #[test]
+    fn submit_runs_actions_without_waiting() {
+        let executor = Executor::new();
+        let path = std::env::temp_dir().join(format!("whichkey-executor-{}", std::process::id()));
+
+        let start = Instant::now();
+        executor.submit(&command("sleep 1"));
+        executor.submit(&command(&format!("touch {}", path.display())));
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        assert!(wait_for(|| path.exists()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn executor_reaps_exited_children() {
+        let executor = Executor::new();
+
+        executor.submit(&command("sleep 0.2"));
+        assert!(wait_for(|| executor.running() == 1));
+        assert!(wait_for(|| executor.running() == 0));
+    }
+}
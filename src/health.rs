@@ -0,0 +1,269 @@
+// Only the event tap of macOS gets disabled by the system, the other
+// backends only report their latency
+#![cfg_attr(not(target_os = "macos"), allow(dead_code))]
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::input::{KeyEvent, KeyEventHandler, Verdict};
+
+// How often the watchdog checks that the input backend still gets the keys
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// Handling a key event slower than this is logged, macOS disables the event
+// tap of a callback that takes about a second
+const SLOW_CALLBACK: Duration = Duration::from_millis(100);
+
+// A tap disabled again within STABLE_FOR of the last time waits before it's
+// re-enabled, from BACKOFF_MIN doubling up to BACKOFF_MAX
+const STABLE_FOR: Duration = Duration::from_secs(30);
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+// The health of the running input backend, reported by `whichkey status`
+pub static HEALTH: Health = Health::new();
+
+// Disable tells why the event tap stopped getting the keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disable {
+    // The callback took too long
+    Timeout,
+    // The system turned it off, e.g. while a password is typed
+    UserInput,
+    // Only the watchdog noticed it
+    Unnoticed,
+}
+
+// Stats are what the watchdog saw so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub callbacks: u64,
+    pub slow_callbacks: u64,
+    pub average_latency: Duration,
+    pub max_latency: Duration,
+    pub disabled_by_timeout: u64,
+    pub disabled_by_user_input: u64,
+    pub disabled_unnoticed: u64,
+    pub backoff: Duration,
+}
+
+// Health keeps track of how fast the key events are handled and how often
+// the event tap gets disabled, and decides when it's re-enabled
+pub struct Health {
+    state: Mutex<State>,
+}
+
+struct State {
+    enabled: bool,
+    callbacks: u64,
+    slow_callbacks: u64,
+    total_latency: Duration,
+    max_latency: Duration,
+    disabled_by_timeout: u64,
+    disabled_by_user_input: u64,
+    disabled_unnoticed: u64,
+    last_disabled: Option<Instant>,
+    backoff: Duration,
+    enable_at: Option<Instant>,
+}
+
+impl Health {
+    pub const fn new() -> Self {
+        Health {
+            state: Mutex::new(State {
+                enabled: false,
+                callbacks: 0,
+                slow_callbacks: 0,
+                total_latency: Duration::ZERO,
+                max_latency: Duration::ZERO,
+                disabled_by_timeout: 0,
+                disabled_by_user_input: 0,
+                disabled_unnoticed: 0,
+                last_disabled: None,
+                backoff: Duration::ZERO,
+                enable_at: None,
+            }),
+        }
+    }
+
+    // handled records how long a key event took to handle
+    pub fn handled(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.callbacks += 1;
+        state.total_latency += latency;
+        state.max_latency = state.max_latency.max(latency);
+        if latency > SLOW_CALLBACK {
+            state.slow_callbacks += 1;
+            log::warn!("Handling a key event took {}ms", latency.as_millis());
+        }
+    }
+
+    // enabled records that the event tap gets the keys again
+    pub fn enabled(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.enabled = true;
+        state.enable_at = None;
+    }
+
+    // disabled records that the event tap stopped getting the keys, and
+    // returns how long to wait before re-enabling it
+    pub fn disabled(&self, reason: Disable, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.disabled(reason, now)
+    }
+
+    // check is the watchdog looking at the event tap, it returns true when
+    // the tap is disabled and it's time to re-enable it
+    pub fn check(&self, tap_enabled: bool, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if tap_enabled {
+            return false;
+        }
+
+        if state.enabled {
+            state.disabled(Disable::Unnoticed, now);
+        }
+        state.enable_at.is_some_and(|at| now >= at)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.state.lock().unwrap();
+        Stats {
+            callbacks: state.callbacks,
+            slow_callbacks: state.slow_callbacks,
+            average_latency: match state.callbacks {
+                0 => Duration::ZERO,
+                n => state.total_latency / n as u32,
+            },
+            max_latency: state.max_latency,
+            disabled_by_timeout: state.disabled_by_timeout,
+            disabled_by_user_input: state.disabled_by_user_input,
+            disabled_unnoticed: state.disabled_unnoticed,
+            backoff: state.backoff,
+        }
+    }
+
+    // watch starts the watchdog, which re-enables the event tap when it's
+    // disabled without its callback being told, or once the backoff is over
+    pub fn watch(
+        &'static self,
+        is_enabled: impl Fn() -> bool + Send + 'static,
+        enable: impl Fn() + Send + 'static,
+    ) {
+        thread::spawn(move || loop {
+            thread::sleep(WATCHDOG_INTERVAL);
+            if self.check(is_enabled(), Instant::now()) {
+                enable();
+            }
+        });
+    }
+}
+
+impl State {
+    fn disabled(&mut self, reason: Disable, now: Instant) -> Duration {
+        match reason {
+            Disable::Timeout => self.disabled_by_timeout += 1,
+            Disable::UserInput => self.disabled_by_user_input += 1,
+            Disable::Unnoticed => self.disabled_unnoticed += 1,
+        }
+
+        let again = self
+            .last_disabled
+            .is_some_and(|last| now.duration_since(last) < STABLE_FOR);
+        self.backoff = match (again, self.backoff.is_zero()) {
+            (false, _) => Duration::ZERO,
+            (true, true) => BACKOFF_MIN,
+            (true, false) => (self.backoff * 2).min(BACKOFF_MAX),
+        };
+        self.last_disabled = Some(now);
+        self.enabled = false;
+        self.enable_at = Some(now + self.backoff);
+
+        log::warn!(
+            "Event tap disabled ({:?}), {} times so far, re-enabling it in {}ms",
+            reason,
+            self.disabled_by_timeout + self.disabled_by_user_input + self.disabled_unnoticed,
+            self.backoff.as_millis()
+        );
+        self.backoff
+    }
+}
+
+// Timed hands the key events to the handler, and records how long it takes
+pub struct Timed(pub Box<dyn KeyEventHandler>);
+
+impl KeyEventHandler for Timed {
+    fn handle(&mut self, event: &KeyEvent) -> Verdict {
+        let start = Instant::now();
+        let verdict = self.0.handle(event);
+        HEALTH.handled(start.elapsed());
+        verdict
+    }
+
+    fn is_pending(&self) -> bool {
+        self.0.is_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_backs_off_while_disabled_again_and_again() {
+        let health = Health::new();
+        let start = Instant::now();
+
+        assert_eq!(health.disabled(Disable::Timeout, start), Duration::ZERO);
+        let backoffs: Vec<Duration> = (1..=8)
+            .map(|i| health.disabled(Disable::Timeout, start + Duration::from_secs(i)))
+            .collect();
+        let secs: Vec<u64> = backoffs.iter().map(|backoff| backoff.as_secs()).collect();
+        assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60]);
+
+        // Stable for long enough, re-enabled right away again
+        let later = start + Duration::from_secs(8) + STABLE_FOR;
+        assert_eq!(health.disabled(Disable::UserInput, later), Duration::ZERO);
+
+        let stats = health.stats();
+        assert_eq!(stats.disabled_by_timeout, 9);
+        assert_eq!(stats.disabled_by_user_input, 1);
+        assert_eq!(stats.backoff, Duration::ZERO);
+    }
+
+    #[test]
+    fn check_notices_disabled_tap_and_waits_for_backoff() {
+        let health = Health::new();
+        let start = Instant::now();
+        health.enabled();
+
+        assert!(!health.check(true, start));
+
+        // Disabled behind the callback's back, re-enabled right away
+        assert!(health.check(false, start));
+        assert_eq!(health.stats().disabled_unnoticed, 1);
+        health.enabled();
+
+        // Again soon after, it waits a second
+        let soon = start + Duration::from_millis(500);
+        assert!(!health.check(false, soon));
+        assert!(!health.check(false, soon + Duration::from_millis(999)));
+        assert!(health.check(false, soon + BACKOFF_MIN));
+        assert_eq!(health.stats().disabled_unnoticed, 2);
+    }
+
+    #[test]
+    fn handled_tracks_latency() {
+        let health = Health::new();
+        for ms in [2, 4, 150] {
+            health.handled(Duration::from_millis(ms));
+        }
+
+        let stats = health.stats();
+        assert_eq!(stats.callbacks, 3);
+        assert_eq!(stats.slow_callbacks, 1);
+        assert_eq!(stats.average_latency, Duration::from_millis(52));
+        assert_eq!(stats.max_latency, Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,241 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::config;
+
+// Hint is a key that can come next in a sequence
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hint {
+    pub key: String,
+    // What the key does: the mapping it fires, or the groups of the
+    // mappings it leads to
+    pub label: String,
+    // The key is the beginning of longer sequences, not a mapping
+    pub is_prefix: bool,
+}
+
+// HintDisplay shows the which-key popup, listing the keys that can come
+// next after the leader key and each prefix
+pub trait HintDisplay: Send {
+    // show replaces the popup with the hints for the keys typed so far. The
+    // sequence is reset after the timeout unless a key is typed, the popup
+    // can go away by then.
+    fn show(&mut self, keys: &[&str], hints: &[Hint], timeout: Duration);
+
+    fn hide(&mut self);
+}
+
+// hint_display returns the display picked in the config, if any
+pub fn hint_display(config: &config::Config) -> Result<Option<Box<dyn HintDisplay>>, String> {
+    let leader_key = &config.leader_key;
+    match config.hints.display {
+        config::HintDisplayKind::None => Ok(None),
+        config::HintDisplayKind::Terminal => Ok(Some(Box::new(TerminalHints::new(
+            std::io::stderr(),
+            leader_key,
+        )))),
+        #[cfg(target_os = "linux")]
+        config::HintDisplayKind::X11 => crate::x11_hints::X11Hints::new(leader_key)
+            .map(|hints| Some(Box::new(hints) as Box<dyn HintDisplay>))
+            .map_err(|err| format!("Failed to open the hint window: {}", err)),
+        #[cfg(not(target_os = "linux"))]
+        config::HintDisplayKind::X11 => Err("The X11 hint window is only on Linux".to_string()),
+    }
+}
+
+// hint_lines lays the hints out the way the renderers draw them, a title
+// with the keys typed so far, then one line per key:
+//
+//     option o
+//     f  → Finder
+//     v  → +Open Applications
+pub fn hint_lines(leader_key: &str, keys: &[&str], hints: &[Hint]) -> Vec<String> {
+    let mut title = vec![leader_key];
+    title.extend(keys);
+
+    let width = hints
+        .iter()
+        .map(|hint| hint.key.chars().count())
+        .max()
+        .unwrap_or_default();
+
+    let mut lines = vec![title.join(" ")];
+    for hint in hints {
+        let prefix = if hint.is_prefix { "+" } else { "" };
+        lines.push(format!(
+            "{:<width$}  → {}{}",
+            hint.key,
+            prefix,
+            hint.label,
+            width = width
+        ));
+    }
+
+    lines
+}
+
+// How often the terminal popup checks if it has to be hidden
+const TICK: Duration = Duration::from_millis(50);
+
+// TerminalHints draws the popup as lines of text at the bottom of a
+// terminal, and erases them once it's hidden or the sequence timed out
+pub struct TerminalHints<W: Write + Send + 'static> {
+    leader_key: String,
+    screen: Arc<Mutex<Screen<W>>>,
+}
+
+// Screen is what the popup drew, shared with the thread erasing it once
+// the sequence timed out
+struct Screen<W: Write + Send> {
+    out: W,
+    drawn: usize,
+    hide_at: Option<Instant>,
+    clock: Box<dyn Clock>,
+}
+
+impl<W: Write + Send + 'static> TerminalHints<W> {
+    pub fn new(out: W, leader_key: &str) -> Self {
+        let screen = Arc::new(Mutex::new(Screen {
+            out,
+            drawn: 0,
+            hide_at: None,
+            clock: Box::new(SystemClock),
+        }));
+
+        let watched = Arc::downgrade(&screen);
+        thread::spawn(move || loop {
+            thread::sleep(TICK);
+            let Some(screen) = watched.upgrade() else {
+                break;
+            };
+            screen.lock().unwrap().expire();
+        });
+
+        TerminalHints {
+            leader_key: leader_key.to_string(),
+            screen,
+        }
+    }
+
+    // with_clock replaces the real time the popup is hidden by
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        self.screen.lock().unwrap().clock = Box::new(clock);
+        self
+    }
+
+    // expire erases the popup once the sequence timed out, the thread of
+    // the popup does it every TICK
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn expire(&self) {
+        self.screen.lock().unwrap().expire();
+    }
+}
+
+impl<W: Write + Send> Screen<W> {
+    fn erase(&mut self) {
+        if self.drawn > 0 {
+            // Move up to the first line drawn, and clear to the end
+            let _ = write!(self.out, "\x1b[{}F\x1b[J", self.drawn);
+            self.drawn = 0;
+        }
+        self.hide_at = None;
+        let _ = self.out.flush();
+    }
+
+    fn expire(&mut self) {
+        if self.hide_at.is_some_and(|at| self.clock.now() >= at) {
+            self.erase();
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> HintDisplay for TerminalHints<W> {
+    fn show(&mut self, keys: &[&str], hints: &[Hint], timeout: Duration) {
+        let mut screen = self.screen.lock().unwrap();
+        screen.erase();
+        let lines = hint_lines(&self.leader_key, keys, hints);
+        for line in lines.iter() {
+            let _ = writeln!(screen.out, "{}", line);
+        }
+        screen.drawn = lines.len();
+        screen.hide_at = Some(screen.clock.now() + timeout);
+        let _ = screen.out.flush();
+    }
+
+    fn hide(&mut self) {
+        self.screen.lock().unwrap().erase();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn hints() -> Vec<Hint> {
+        vec![
+            Hint {
+                key: "f".to_string(),
+                label: "Finder".to_string(),
+                is_prefix: false,
+            },
+            Hint {
+                key: "space".to_string(),
+                label: "Open Applications".to_string(),
+                is_prefix: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn hint_lines_align_labels() {
+        assert_eq!(
+            hint_lines("option", &["o"], &hints()),
+            ["option o", "f      → Finder", "space  → +Open Applications"]
+        );
+    }
+
+    #[test]
+    fn terminal_hints_erase_what_they_drew() {
+        let mut display = TerminalHints::new(Vec::new(), "option");
+
+        display.show(&["o"], &hints(), Duration::from_secs(1));
+        display.show(&[], &hints()[..1], Duration::from_secs(1));
+        display.hide();
+        display.hide();
+
+        let out = String::from_utf8(display.screen.lock().unwrap().out.clone()).unwrap();
+        assert_eq!(
+            out,
+            "option o\nf      → Finder\nspace  → +Open Applications\n\
+             \x1b[3F\x1b[Joption\nf  → Finder\n\
+             \x1b[2F\x1b[J"
+        );
+    }
+
+    #[test]
+    fn terminal_hints_erase_once_sequence_timed_out() {
+        let clock = ManualClock::new();
+        let mut display = TerminalHints::new(Vec::new(), "option").with_clock(clock.clone());
+        let drawn = |display: &TerminalHints<Vec<u8>>| {
+            String::from_utf8(display.screen.lock().unwrap().out.clone()).unwrap()
+        };
+
+        display.show(&[], &hints()[..1], Duration::from_secs(1));
+        clock.advance(Duration::from_millis(999));
+        display.expire();
+        assert_eq!(drawn(&display), "option\nf  → Finder\n");
+
+        clock.advance(Duration::from_millis(1));
+        display.expire();
+        assert_eq!(drawn(&display), "option\nf  → Finder\n\x1b[2F\x1b[J");
+
+        // Already erased, hiding it draws nothing
+        display.hide();
+        assert_eq!(drawn(&display), "option\nf  → Finder\n\x1b[2F\x1b[J");
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::Error;
+use std::time::Instant;
+
+use crate::config;
+use crate::layout::Layout;
+
+// KeyState tells if a key went down or up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Down,
+    Up,
+}
+
+// Modifiers are the modifier keys held when a key event happened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub control: bool,
+    pub option: bool,
+    pub command: bool,
+}
+
+// KeyEvent is a key press or release, normalized by the input backend so
+// the sequence logic doesn't depend on any platform.
+//
+// Keys are named the way the config names them: letters, digits and
+// punctuation by their character ("a", "1", ";"), other keys by name
+// ("space", "return", "escape", "left", "f18", "keypad1"), and modifiers
+// by their side ("option", "rightoption", "command", "shift", "control",
+// "fn", "capslock"). A mouse button going down is MOUSE_CLICK, so a click
+// breaks a tap of the leader key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyEvent {
+    pub key: String,
+    pub modifiers: Modifiers,
+    pub state: KeyState,
+    pub timestamp: Instant,
+}
+
+// The name of a mouse button going down, it's no key of the mappings
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub const MOUSE_CLICK: &str = "click";
+
+// Verdict tells the input backend what to do with the key event it just
+// delivered
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    // Let the key event through
+    Pass,
+    // Swallow the key event
+    Consume,
+    // Swallow the key event and send these ones instead, used to give back
+    // the keys of an aborted sequence
+    Replay(Vec<KeyEvent>),
+}
+
+// KeyEventHandler decides what happens to the key events, the sequence
+// recorder is the one whichkey runs with
+pub trait KeyEventHandler: Send {
+    fn handle(&mut self, event: &KeyEvent) -> Verdict;
+
+    // is_pending tells if a sequence is under way. Backends that can't
+    // swallow a key event once they see it grab the keyboard meanwhile.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn is_pending(&self) -> bool;
+}
+
+// InputBackend is the platform specific part of whichkey, it captures the
+// key events system wide and applies the verdicts of the handler
+pub trait InputBackend {
+    // run delivers every key event to the handler until the backend stops
+    fn run(&mut self, handler: Box<dyn KeyEventHandler>) -> Result<(), Error>;
+}
+
+// default_backend returns the input backend of the current platform, if
+// there is one
+#[allow(unused_variables)]
+pub fn default_backend(config: &config::Config) -> Option<Box<dyn InputBackend>> {
+    #[cfg(target_os = "macos")]
+    {
+        Some(Box::new(crate::event_tap::EventTapBackend::new(
+            config.logging.debug_keys,
+            layout(config, crate::event_tap::current_layout),
+        )))
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        match config.input.backend {
+            config::Backend::Evdev => {
+                let layout = layout(config, || {
+                    let keymap = crate::xkb::Keymap::from_names(
+                        config.input.layout.as_deref(),
+                        config.input.variant.as_deref(),
+                    )?;
+                    Ok(keymap.layout(crate::evdev_backend::key_names()))
+                });
+                Some(Box::new(crate::evdev_backend::EvdevBackend::new(
+                    config.input.devices.clone(),
+                    layout,
+                )))
+            }
+            // The X server knows the layout, the backend reads it itself
+            config::Backend::X11 => Some(Box::new(crate::x11_backend::X11Backend::new(
+                config.input.key_names,
+            ))),
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        None
+    }
+}
+
+// layout builds the keyboard layout the keys are named after, none when
+// they're named by their position. Failing to build it, the keys are named
+// by their position too.
+#[cfg_attr(not(any(target_os = "macos", target_os = "linux")), allow(dead_code))]
+fn layout(
+    config: &config::Config,
+    build: impl FnOnce() -> Result<Layout, Error>,
+) -> Option<Layout> {
+    if config.input.key_names == config::KeyNames::Position {
+        return None;
+    }
+
+    match build() {
+        Ok(layout) => Some(layout),
+        Err(err) => {
+            log::warn!(
+                "Failed to read the keyboard layout, keys are named by their position on a US keyboard: {}",
+                err
+            );
+            None
+        }
+    }
+}
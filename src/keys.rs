@@ -0,0 +1,326 @@
+use std::ops::RangeInclusive;
+
+use crate::input::Modifiers;
+
+// Keys the input backends name with a single character, the ones of a US
+// keyboard
+const TYPABLE_KEYS: &str = "abcdefghijklmnopqrstuvwxyz0123456789-=[]\\;',./`";
+
+// Keys that only change the keys they're held with
+pub const MODIFIER_KEYS: &[&str] = &[
+    "shift",
+    "rightshift",
+    "control",
+    "rightcontrol",
+    "option",
+    "rightoption",
+    "command",
+    "rightcommand",
+    "fn",
+];
+
+// Keys named by more than one character, besides the function keys and the
+// digits of the keypad
+const NAMED_KEYS: &[&str] = &[
+    "space",
+    "return",
+    "tab",
+    "escape",
+    "delete",
+    "forwarddelete",
+    "capslock",
+    "help",
+    "home",
+    "end",
+    "pageup",
+    "pagedown",
+    "left",
+    "right",
+    "up",
+    "down",
+    "keypad.",
+    "keypad*",
+    "keypad+",
+    "keypad/",
+    "keypad-",
+    "keypad=",
+    "keypadclear",
+    "keypadenter",
+];
+
+// Other names of the keys, the ones of vim
+const ALIASES: &[(&str, &str)] = &[
+    ("cr", "return"),
+    ("enter", "return"),
+    ("esc", "escape"),
+    ("bs", "delete"),
+    ("backspace", "delete"),
+    ("del", "forwarddelete"),
+];
+
+pub fn is_modifier(key: &str) -> bool {
+    MODIFIER_KEYS.contains(&key)
+}
+
+// is_key_name tells if the input backends may name a key so, the way the
+// leader key is written: a modifier, a named key or a lowercase character
+pub fn is_key_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => !c.is_whitespace() && !c.is_control() && !c.is_uppercase(),
+        _ => is_modifier(name) || is_named_key(name),
+    }
+}
+
+// token names a key with the modifiers held, the way the mappings are keyed.
+// Shifted letters are uppercase, other keys held with modifiers are written
+// like vim does, with C for control, M for option, D for command and S for
+// shift, e.g. "o", "O", "<space>", "<C-a>", "<M-S-return>", "<D-O>".
+pub fn token(key: &str, modifiers: Modifiers) -> String {
+    let mut modifiers = modifiers;
+    let mut key = key.to_string();
+    if modifiers.shift {
+        if let Some(upper) = uppercase(&key) {
+            key = upper.to_string();
+            modifiers.shift = false;
+        }
+    }
+
+    let prefix: String = [
+        (modifiers.control, "C-"),
+        (modifiers.option, "M-"),
+        (modifiers.command, "D-"),
+        (modifiers.shift, "S-"),
+    ]
+    .iter()
+    .filter(|(held, _)| *held)
+    .map(|(_, prefix)| *prefix)
+    .collect();
+
+    if prefix.is_empty() && key.chars().count() == 1 {
+        key
+    } else {
+        format!("<{}{}>", prefix, key)
+    }
+}
+
+// uppercase returns the uppercase letter of a key that's a lowercase one
+fn uppercase(key: &str) -> Option<char> {
+    let mut chars = key.chars();
+    let (Some(c), None) = (chars.next(), chars.next()) else {
+        return None;
+    };
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(upper), None) if c.is_lowercase() => Some(upper),
+        _ => None,
+    }
+}
+
+// parse splits the keys of a mapping into tokens, one per key typed. A
+// character is a key, uppercase for a shifted letter, and a key with a
+// name or held with modifiers goes between angle brackets: "o<S-f>",
+// "<space>", "<C-a>", "gG". Modifiers and names are case insensitive, and
+// vim's names like "<cr>" and "<esc>" work too.
+pub fn parse(keys: &str) -> Result<Vec<String>, String> {
+    Ok(parse_keys(keys)?
+        .iter()
+        .map(|(key, modifiers)| token(key, *modifiers))
+        .collect())
+}
+
+// untypable returns the first character of the keys that's not a key of
+// a US keyboard, they can never be typed when keys are named by position
+pub fn untypable(keys: &str) -> Option<char> {
+    parse_keys(keys)
+        .ok()?
+        .iter()
+        .filter_map(|(key, _)| {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !TYPABLE_KEYS.contains(c) => Some(c),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn parse_keys(keys: &str) -> Result<Vec<(String, Modifiers)>, String> {
+    let mut parsed = vec![];
+    let mut rest = keys;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let Some(end) = rest.find('>') else {
+                return Err(format!("{:?} misses its closing '>'", rest));
+            };
+            parsed.push(parse_bracketed(&rest[1..end])?);
+            rest = &rest[end + 1..];
+        } else {
+            parsed.push(parse_key(&c.to_string(), Modifiers::default())?);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    Ok(parsed)
+}
+
+// parse_bracketed parses the inside of "<C-S-a>"
+fn parse_bracketed(inner: &str) -> Result<(String, Modifiers), String> {
+    let mut modifiers = Modifiers::default();
+    let mut name = inner;
+    while name.len() > 2 && name.as_bytes()[1] == b'-' {
+        match name.as_bytes()[0].to_ascii_uppercase() {
+            b'C' => modifiers.control = true,
+            b'M' | b'A' => modifiers.option = true,
+            b'D' => modifiers.command = true,
+            b'S' => modifiers.shift = true,
+            _ => {
+                return Err(format!(
+                    "<{}>: {:?} is not a modifier, use C, M, D or S",
+                    inner,
+                    &name[..1]
+                ))
+            }
+        }
+        name = &name[2..];
+    }
+
+    parse_key(name, modifiers).map_err(|err| format!("<{}>: {}", inner, err))
+}
+
+// parse_key names a key the way the backends do, lowercase and shifted for
+// an uppercase letter. Any other character is a key too, the keyboard
+// layout may type it.
+fn parse_key(name: &str, mut modifiers: Modifiers) -> Result<(String, Modifiers), String> {
+    let mut chars = name.chars();
+    let key = match (chars.next(), chars.next()) {
+        (None, _) => return Err("no key".to_string()),
+        (Some(c), None) if c.is_whitespace() || c.is_control() => {
+            return Err(format!("{:?} is not a key name", c));
+        }
+        (Some(c), None) => {
+            let lower: String = c.to_lowercase().collect();
+            if c.is_uppercase() && uppercase(&lower) == Some(c) {
+                modifiers.shift = true;
+                lower
+            } else {
+                c.to_string()
+            }
+        }
+        _ => {
+            let name = name.to_ascii_lowercase();
+            let name = ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == name)
+                .map_or(name, |(_, key)| key.to_string());
+            if is_modifier(&name) {
+                return Err(format!(
+                    "{:?} is a modifier, hold it with a key like <S-a>",
+                    name
+                ));
+            }
+            if !is_named_key(&name) {
+                return Err(format!("{:?} is not a key name", name));
+            }
+            name
+        }
+    };
+
+    Ok((key, modifiers))
+}
+
+fn is_named_key(name: &str) -> bool {
+    // "f1" to "f20" and "keypad0" to "keypad9", without leading zeros
+    let numbered = |prefix: &str, range: RangeInclusive<u32>| {
+        name.strip_prefix(prefix)
+            .and_then(|n| n.parse::<u32>().ok())
+            .is_some_and(|n| range.contains(&n) && name == format!("{}{}", prefix, n))
+    };
+
+    NAMED_KEYS.contains(&name) || numbered("f", 1..=20) || numbered("keypad", 0..=9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(shift: bool, control: bool, option: bool, command: bool) -> Modifiers {
+        Modifiers {
+            shift,
+            control,
+            option,
+            command,
+        }
+    }
+
+    #[test]
+    fn token_names_keys_with_modifiers() {
+        assert_eq!(token("o", Modifiers::default()), "o");
+        assert_eq!(token("o", held(true, false, false, false)), "O");
+        assert_eq!(token(";", held(true, false, false, false)), "<S-;>");
+        assert_eq!(token("space", Modifiers::default()), "<space>");
+        assert_eq!(token("a", held(false, true, false, false)), "<C-a>");
+        assert_eq!(token("a", held(true, true, true, true)), "<C-M-D-A>");
+        assert_eq!(
+            token("return", held(true, false, true, false)),
+            "<M-S-return>"
+        );
+    }
+
+    #[test]
+    fn parse_splits_keys_into_tokens() {
+        let parsed = |keys: &str| parse(keys).unwrap();
+
+        assert_eq!(parsed("ovs"), ["o", "v", "s"]);
+        assert_eq!(parsed("gG"), ["g", "G"]);
+        assert_eq!(parsed("o<S-f>"), ["o", "F"]);
+        assert_eq!(
+            parsed("<space><Space><CR>"),
+            ["<space>", "<space>", "<return>"]
+        );
+        assert_eq!(parsed("<C-a><c-A><s-c-a>"), ["<C-a>", "<C-A>", "<C-A>"]);
+        assert_eq!(parsed("<A-x><M-x>"), ["<M-x>", "<M-x>"]);
+        assert_eq!(
+            parsed("<C-->x<f12><keypad1>"),
+            ["<C-->", "x", "<f12>", "<keypad1>"]
+        );
+        assert_eq!(parsed("!ü<S-ü>É"), ["!", "ü", "Ü", "É"]);
+        assert!(parsed("").is_empty());
+    }
+
+    #[test]
+    fn is_key_name_accepts_any_key_as_leader() {
+        for name in ["option", "rightoption", "fn", "capslock", "f18", "a", ";"] {
+            assert!(is_key_name(name), "{}", name);
+        }
+        for name in ["alt", "<f18>", "A", " ", ""] {
+            assert!(!is_key_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn untypable_finds_keys_of_other_layouts() {
+        assert_eq!(untypable("o<S-f><space>;"), None);
+        assert_eq!(untypable("o!"), Some('!'));
+        assert_eq!(untypable("<C-ü>"), Some('ü'));
+    }
+
+    #[test]
+    fn parse_reports_keys_that_cant_be_typed() {
+        let error = |keys: &str| parse(keys).unwrap_err();
+
+        assert_eq!(error("o p"), "' ' is not a key name");
+        assert_eq!(error("o<C-a"), r#""<C-a" misses its closing '>'"#);
+        assert_eq!(
+            error("<X-a>"),
+            r#"<X-a>: "X" is not a modifier, use C, M, D or S"#
+        );
+        assert_eq!(error("<spce>"), r#"<spce>: "spce" is not a key name"#);
+        assert_eq!(error("<f21>"), r#"<f21>: "f21" is not a key name"#);
+        assert_eq!(error("<>"), "<>: no key");
+        assert_eq!(
+            error("<shift>"),
+            r#"<shift>: "shift" is a modifier, hold it with a key like <S-a>"#
+        );
+    }
+}
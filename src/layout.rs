@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use crate::input::{KeyEvent, KeyEventHandler, Verdict};
+
+// Layout renames the keys, named by the input backend after their position
+// on a US keyboard, to the characters the keyboard layout types with them,
+// alone and with shift. On a French AZERTY layout, "q" is "a" and "A", and
+// "1" is "&" and "1".
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Layout {
+    chars: HashMap<String, (String, Option<String>)>,
+    // The other way around, for the keys given back to the backend, with
+    // whether shift types them
+    positions: HashMap<String, (String, bool)>,
+}
+
+impl Layout {
+    // insert names the key at the position after the characters it types,
+    // keys that type nothing, like dead keys, or blanks keep their name
+    pub fn insert(&mut self, position: &str, char: Option<char>, shifted: Option<char>) {
+        let printable = |c: &char| !c.is_whitespace() && !c.is_control() && *c != '<';
+        let Some(char) = char.filter(printable) else {
+            return;
+        };
+        let shifted = shifted.filter(printable).map(String::from);
+
+        self.positions
+            .entry(char.to_string())
+            .or_insert((position.to_string(), false));
+        if let Some(shifted) = shifted.as_ref() {
+            self.positions
+                .entry(shifted.clone())
+                .or_insert((position.to_string(), true));
+        }
+        self.chars
+            .insert(position.to_string(), (char.to_string(), shifted));
+    }
+
+    // rename names the key event after the character typed. Shift is
+    // dropped from the modifiers when it types a character of its own, so
+    // shift and "1" is "!" on a US layout.
+    pub fn rename(&self, event: &KeyEvent) -> KeyEvent {
+        let mut event = event.clone();
+        match self.chars.get(&event.key) {
+            Some((_, Some(shifted))) if event.modifiers.shift => {
+                event.key = shifted.clone();
+                event.modifiers.shift = false;
+            }
+            Some((char, _)) => event.key = char.clone(),
+            None => {}
+        }
+
+        event
+    }
+
+    // restore names the renamed key event after its position again
+    pub fn restore(&self, event: &KeyEvent) -> KeyEvent {
+        let mut event = event.clone();
+        if let Some((position, shifted)) = self.positions.get(&event.key) {
+            event.key = position.clone();
+            event.modifiers.shift |= shifted;
+        }
+
+        event
+    }
+}
+
+// Localized hands the key events to the handler renamed after the layout,
+// and the ones it gives back named after their position again
+pub struct Localized {
+    handler: Box<dyn KeyEventHandler>,
+    layout: Layout,
+}
+
+impl Localized {
+    pub fn new(handler: Box<dyn KeyEventHandler>, layout: Layout) -> Self {
+        Localized { handler, layout }
+    }
+}
+
+// localize wraps the handler in Localized when there's a layout, keys named
+// by their position go to the handler as they are
+pub fn localize(
+    handler: Box<dyn KeyEventHandler>,
+    layout: Option<Layout>,
+) -> Box<dyn KeyEventHandler> {
+    match layout {
+        Some(layout) => Box::new(Localized::new(handler, layout)),
+        None => handler,
+    }
+}
+
+impl KeyEventHandler for Localized {
+    fn handle(&mut self, event: &KeyEvent) -> Verdict {
+        match self.handler.handle(&self.layout.rename(event)) {
+            Verdict::Replay(events) => Verdict::Replay(
+                events
+                    .iter()
+                    .map(|event| self.layout.restore(event))
+                    .collect(),
+            ),
+            verdict => verdict,
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.handler.is_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{KeyState, Modifiers};
+    use std::time::Instant;
+
+    fn key(name: &str, shift: bool) -> KeyEvent {
+        KeyEvent {
+            key: name.to_string(),
+            modifiers: Modifiers {
+                shift,
+                ..Modifiers::default()
+            },
+            state: KeyState::Down,
+            timestamp: Instant::now(),
+        }
+    }
+
+    fn azerty() -> Layout {
+        let mut layout = Layout::default();
+        layout.insert("q", Some('a'), Some('A'));
+        layout.insert("a", Some('q'), Some('Q'));
+        layout.insert("1", Some('&'), Some('1'));
+        layout.insert("[", None, None);
+        layout.insert("space", Some(' '), Some(' '));
+        layout
+    }
+
+    #[test]
+    fn rename_names_keys_after_layout() {
+        let layout = azerty();
+        let renamed = |name: &str, shift: bool| {
+            let event = layout.rename(&key(name, shift));
+            (event.key, event.modifiers.shift)
+        };
+
+        assert_eq!(renamed("q", false), ("a".to_string(), false));
+        assert_eq!(renamed("q", true), ("A".to_string(), false));
+        assert_eq!(renamed("1", false), ("&".to_string(), false));
+        assert_eq!(renamed("1", true), ("1".to_string(), false));
+        assert_eq!(renamed("[", false), ("[".to_string(), false));
+        assert_eq!(renamed("space", true), ("space".to_string(), true));
+    }
+
+    #[test]
+    fn restore_names_keys_after_position() {
+        let layout = azerty();
+        for (name, shift) in [("q", false), ("q", true), ("1", true), ("space", false)] {
+            let event = key(name, shift);
+            assert_eq!(layout.restore(&layout.rename(&event)), event);
+        }
+    }
+}
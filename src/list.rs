@@ -0,0 +1,138 @@
+use crate::config;
+
+// Format is how the cheat sheet of the config is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // Aligned columns, for the terminal
+    Text,
+    // Tables, to paste in notes or a wiki
+    Markdown,
+}
+
+// cheat_sheet lists the labeled prefixes and the mappings of every group,
+// each with its keys and description:
+//
+//     Prefixes
+//       o    Open apps
+//
+//     Open Applications
+//       of   Finder
+//       ovs  Visual Studio Code
+pub fn cheat_sheet(config: &config::Config, format: Format) -> String {
+    let mut sections = vec![];
+
+    let mut prefixes: Vec<(&str, &str)> = config
+        .prefixes
+        .iter()
+        .map(|(keys, label)| (keys.as_str(), label.as_str()))
+        .collect();
+    prefixes.sort();
+    if !prefixes.is_empty() {
+        sections.push(section("Prefixes", &prefixes, format));
+    }
+
+    for group in config.groups.iter() {
+        let rows: Vec<(&str, &str)> = group
+            .mappings
+            .iter()
+            .map(|mapping| (mapping.keys.as_str(), mapping.label()))
+            .collect();
+        sections.push(section(&group.name, &rows, format));
+    }
+
+    match format {
+        Format::Text => sections.join("\n"),
+        Format::Markdown => format!("# {}\n\n{}", config.leader_key, sections.join("\n")),
+    }
+}
+
+fn section(title: &str, rows: &[(&str, &str)], format: Format) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            let width = rows
+                .iter()
+                .map(|(keys, _)| keys.chars().count())
+                .max()
+                .unwrap_or_default();
+
+            out.push_str(title);
+            out.push('\n');
+            for (keys, label) in rows {
+                out.push_str(&format!("  {:<width$}  {}\n", keys, label, width = width));
+            }
+        }
+        Format::Markdown => {
+            out.push_str(&format!(
+                "## {}\n\n| Keys | Description |\n| --- | --- |\n",
+                title
+            ));
+            for (keys, label) in rows {
+                out.push_str(&format!("| `{}` | {} |\n", keys, label.replace('|', "\\|")));
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+leader_key = "option"
+
+[prefixes]
+o = "Open apps"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "ovs"
+  kind = "Application"
+  command = "Visual Studio Code"
+  description = "Editor"
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "rt"
+  kind = "Command"
+  command = "echo a | wc"
+"#;
+
+    #[test]
+    fn cheat_sheet_lists_prefixes_and_mappings() {
+        let config: config::Config = toml::from_str(CONFIG).unwrap();
+
+        assert_eq!(
+            cheat_sheet(&config, Format::Text),
+            "Prefixes\n  o  Open apps\n\n\
+             Open Applications\n  of   Finder\n  ovs  Editor\n\n\
+             Run commands\n  rt  echo a | wc\n"
+        );
+    }
+
+    #[test]
+    fn cheat_sheet_exports_markdown_tables() {
+        let config: config::Config = toml::from_str(CONFIG).unwrap();
+
+        assert_eq!(
+            cheat_sheet(&config, Format::Markdown),
+            "# option\n\n\
+             ## Prefixes\n\n| Keys | Description |\n| --- | --- |\n| `o` | Open apps |\n\n\
+             ## Open Applications\n\n| Keys | Description |\n| --- | --- |\n\
+             | `of` | Finder |\n| `ovs` | Editor |\n\n\
+             ## Run commands\n\n| Keys | Description |\n| --- | --- |\n\
+             | `rt` | echo a \\| wc |\n"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use simplelog::SharedLogger;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config;
+
+// FileLogger writes the log to a file, as text or JSON lines, and rotates
+// it once it's too big
+pub struct FileLogger {
+    level: LevelFilter,
+    format: config::LogFormat,
+    file: Mutex<RotatingFile>,
+}
+
+impl FileLogger {
+    pub fn new(level: LevelFilter, format: config::LogFormat, file: RotatingFile) -> Box<Self> {
+        Box::new(FileLogger {
+            level,
+            format,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format_record(record, self.format, chrono::Local::now());
+        if let Err(err) = self.file.lock().unwrap().write_line(&line) {
+            eprintln!("Failed to write the log: {}", err);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().file.flush();
+    }
+}
+
+impl SharedLogger for FileLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+// format_record lays a record out as a line of the log, e.g.
+//
+//     2025-09-14T10:12:03.120+08:00 INFO  whichkey::sequence: Reloading config
+//     {"time":"2025-09-14T10:12:03.120+08:00","level":"INFO","target":"whichkey::sequence","message":"Reloading config"}
+fn format_record(
+    record: &Record,
+    format: config::LogFormat,
+    time: chrono::DateTime<chrono::Local>,
+) -> String {
+    let time = time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+    match format {
+        config::LogFormat::Text => format!(
+            "{} {:<5} {}: {}",
+            time,
+            record.level(),
+            record.target(),
+            record.args()
+        ),
+        config::LogFormat::Json => json!({
+            "time": time,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        })
+        .to_string(),
+    }
+}
+
+// RotatingFile appends to the log file, and once it would grow past the max
+// size, moves it to <path>.1, <path>.1 to <path>.2, and so on, dropping the
+// oldest one past max_files. Only the user can read them.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    // open starts a new log file, the one of the last run is rotated so it
+    // survives the restart
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            file: append(path)?,
+            size: 0,
+        };
+        if fs::metadata(path)?.len() > 0 {
+            file.rotate()?;
+        }
+
+        Ok(file)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), Error> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated(i);
+                if from.exists() {
+                    fs::rename(from, self.rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        PathBuf::from(path)
+    }
+}
+
+fn append(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("whichkey-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotating_file_keeps_max_files() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("whichkey.log");
+        fs::write(&path, "last run\n").unwrap();
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["one", "two", "three", "four"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "four\n");
+        assert_eq!(read(dir.join("whichkey.log.1")), "three\n");
+        assert_eq!(read(dir.join("whichkey.log.2")), "one\ntwo\n");
+        assert!(!dir.join("whichkey.log.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn format_record_as_text_or_json() {
+        let time = chrono::Local.timestamp_millis_opt(0).unwrap();
+        let args = format_args!("Reloading \"config\"");
+        let record = Record::builder()
+            .args(args)
+            .level(log::Level::Info)
+            .target("whichkey::reload")
+            .build();
+        let stamp = time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+
+        assert_eq!(
+            format_record(&record, config::LogFormat::Text, time),
+            format!("{} INFO  whichkey::reload: Reloading \"config\"", stamp)
+        );
+        assert_eq!(
+            format_record(&record, config::LogFormat::Json, time),
+            format!(
+                r#"{{"level":"INFO","message":"Reloading \"config\"","target":"whichkey::reload","time":"{}"}}"#,
+                stamp
+            )
+        );
+    }
+}
@@ -1,37 +1,119 @@
-use core_foundation::base::CFRelease;
-use core_foundation::runloop::{CFRunLoopAddSource, CFRunLoopGetCurrent, CFRunLoopRun};
 use simplelog::{
-    ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode, WriteLogger,
+    ColorChoice, CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, TerminalMode,
 };
-use std::fs::File;
-use std::os::raw::c_void;
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::os::unix::net::UnixStream;
+use std::panic;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::process::Command;
-use std::ptr;
+use std::sync::{mpsc, Arc, Mutex};
 use std::{fs, io::Error};
 use std::{thread, time};
 
 // Declare the modules
+#[cfg(target_os = "macos")]
 mod accessibility;
+#[cfg(target_os = "macos")]
 mod cf_utils;
+mod check;
+mod clock;
 mod config;
+mod control;
+#[cfg(target_os = "linux")]
+mod evdev_backend;
+#[cfg(target_os = "macos")]
 mod event_tap;
+mod executor;
+mod health;
+mod hint;
+mod input;
+mod keys;
+mod layout;
+mod list;
+mod logging;
+mod reload;
 mod sequence;
+mod simulate;
+#[cfg(target_os = "macos")]
 mod utils;
+#[cfg(target_os = "linux")]
+mod x11_backend;
+#[cfg(target_os = "linux")]
+mod x11_hints;
+#[cfg(target_os = "linux")]
+mod xkb;
 
-// Import necessary items
-use accessibility::*;
-use cf_utils::core_foundation_private::kCFRunLoopCommonModes;
-use event_tap::*;
-use utils::open_accessibility_preferences;
+use sequence::KeyStrokeRecorder;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand};
 
 #[derive(Parser, Debug)]
+#[command(version, about = "Leader key sequences, system wide")]
 struct Args {
-    /// Command are install, start, stop, and restart
-    command: String,
+    /// Config file to use, instead of $WHICHKEY_CONFIG or
+    /// $XDG_CONFIG_HOME/whichkey/config.toml
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Generate the config and install the launchd service
+    Install,
+    /// Capture the keys and run the mappings, in this terminal
+    Run {
+        /// Log level, e.g. error, info or debug
+        #[arg(long, value_name = "LEVEL")]
+        log_level: Option<LevelFilter>,
+    },
+    /// Start the launchd service
+    Start {
+        /// Run in this terminal instead of as a service
+        #[arg(long)]
+        foreground: bool,
+        /// Log level when running in the foreground
+        #[arg(long, value_name = "LEVEL", requires = "foreground")]
+        log_level: Option<LevelFilter>,
+    },
+    /// Stop the launchd service
+    Stop,
+    /// Stop and start the launchd service
+    Restart,
+    /// Tell if the running whichkey is paused or in a sequence
+    Status,
+    /// Let every key through until resumed
+    Pause,
+    /// Capture the keys again after a pause
+    Resume,
+    /// Make the running whichkey read the config again
+    Reload,
+    /// Print the events of the running whichkey as they happen
+    Events,
+    /// Fire the mapping of the keys, as if typed after the leader key
+    Trigger {
+        keys: String,
+        /// Print the action the keys resolve to instead of running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Report the problems of the config
+    Check {
+        /// Config to check, the one in use by default
+        path: Option<PathBuf>,
+    },
+    /// Print a cheat sheet of the mappings
+    List {
+        /// Export it as markdown tables
+        #[arg(long)]
+        markdown: bool,
+    },
+    /// Play a script of key events through the config
+    Simulate { file: PathBuf },
+    /// Generate the completions of a shell
+    Completions { shell: clap_complete::Shell },
 }
 
 // Type Aliases & Structs for C Types are now in accessibility.rs and event_tap.rs
@@ -47,35 +129,89 @@ struct Args {
 
 fn main() {
     let args = Args::parse();
-    match args.command.as_str() {
-        "install" => install(),
-        "run" => run(),
-        "start" => start(),
-        "stop" => stop(),
-        "restart" => restart(),
-        _ => {
-            println!("invalid command");
+    // Only the commands reading the config need its path, the others work
+    // without a home
+    let config_path = || match config::path(args.config.as_deref()) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(2);
+        }
+    };
+
+    match args.command {
+        Commands::Install => install(&config_path()),
+        Commands::Run { log_level } => run(&config_path(), log_level),
+        Commands::Start {
+            foreground: true,
+            log_level,
+        } => run(&config_path(), log_level),
+        Commands::Start { .. } => start(),
+        Commands::Stop => stop(),
+        Commands::Restart => restart(),
+        Commands::Simulate { file } => simulate(&config_path(), &file),
+        Commands::List { markdown } => list(&config_path(), markdown),
+        Commands::Check { path } => check(&path.unwrap_or_else(config_path)),
+        Commands::Status => control(control::Request::Status),
+        Commands::Pause => control(control::Request::Pause),
+        Commands::Resume => control(control::Request::Resume),
+        Commands::Reload => control(control::Request::Reload),
+        Commands::Events => events(),
+        Commands::Trigger { keys, dry_run } => trigger(&config_path(), &keys, dry_run),
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Args::command(), "whichkey", &mut io::stdout())
         }
     }
 }
 
-fn run() {
-    // Determine log path
-    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-    let log_path = PathBuf::from(home_dir).join("whichkey.log");
+// run captures the keys until whichkey is stopped. The log level given
+// overrides the one of the config.
+fn run(config_path: &Path, log_level: Option<LevelFilter>) {
+    let config = config::Config::load(config_path);
+    let logging = &config.logging;
+    let level = log_level.unwrap_or(logging.level.into());
+    let log_path = match logging.file.clone() {
+        Some(path) => path,
+        None => {
+            let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+            PathBuf::from(home_dir).join("whichkey.log")
+        }
+    };
 
-    // Initialize simplelog
-    let log_file = File::create(&log_path).expect("Failed to create log file");
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Info,
+    // The log of the last run is rotated, and only the user can read them
+    let log_file = match logging::RotatingFile::open(&log_path, logging.max_size, logging.max_files)
+    {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!(
+                "Error: Failed to open {}: {}. Exiting.",
+                log_path.display(),
+                err
+            );
+            process::exit(1);
+        }
+    };
+    // The log goes to the terminal too when run in one, not when started
+    // by launchd, which would keep a copy of it nothing rotates
+    let mut loggers: Vec<Box<dyn SharedLogger>> =
+        vec![logging::FileLogger::new(level, logging.format, log_file)];
+    if io::stderr().is_terminal() {
+        loggers.push(TermLogger::new(
+            level,
             Config::default(),
             TerminalMode::Mixed,
             ColorChoice::Auto,
-        ),
-        WriteLogger::new(LevelFilter::Debug, Config::default(), log_file),
-    ])
-    .expect("Failed to initialize logger");
+        ));
+    }
+    CombinedLogger::init(loggers).expect("Failed to initialize logger");
+
+    // Panics go to the log too, not only to stderr
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        log::error!("{}", info);
+        log::logger().flush();
+        default_hook(info);
+    }));
 
     // Log initial messages using the new logger
     log::info!("----- Starting Whichkey -----");
@@ -85,111 +221,235 @@ fn run() {
         std::env::current_exe().unwrap_or_default()
     );
 
-    unsafe {
-        // Check if accessibility is enabled using functions from accessibility module
-        let api_enabled = ax_api_enabled();
-        let process_trusted = ax_is_process_trusted();
+    let Some(mut backend) = input::default_backend(&config) else {
+        log::error!("No input backend for this platform.");
+        eprintln!("Error: No input backend for this platform. Exiting.");
+        process::exit(1);
+    };
 
-        log::debug!("Accessibility API status:");
-        log::debug!("- AXAPIEnabled: {}", api_enabled);
-        log::debug!("- AXIsProcessTrusted: {}", process_trusted);
+    let display = match hint::hint_display(&config) {
+        Ok(display) => display,
+        Err(err) => {
+            log::error!("{}", err);
+            eprintln!("Error: {}. Exiting.", err);
+            process::exit(1);
+        }
+    };
 
-        if !api_enabled || !process_trusted {
-            log::warn!("Accessibility permissions may not be properly enabled.");
-            log::info!("Opening System Settings...");
+    let listener = match control::listen(&control::socket_path()) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to open the control socket: {}", err);
+            eprintln!(
+                "Error: Failed to open the control socket: {}. Exiting.",
+                err
+            );
+            process::exit(1);
+        }
+    };
+
+    let (sender, reloads) = mpsc::channel();
+    reload::watch(config_path.to_path_buf(), reload::POLL_INTERVAL, sender);
+
+    // The backend only matches the keys, the actions run on the executor
+    let executor = executor::Executor::new();
+    let (sender, events) = mpsc::channel();
+    let mut recorder = KeyStrokeRecorder::with_config(config)
+        .on_fire(move |mapping| executor.submit(&mapping.action))
+        .with_reloads(reloads)
+        .with_events(sender);
+    if let Some(display) = display {
+        recorder = recorder.with_display(display);
+    }
 
-            println!("Accessibility permissions are required.");
-            println!("Please add this app to System Settings → Privacy & Security → Accessibility");
-            println!("Opening System Settings now...");
+    let recorder = control::SharedRecorder(Arc::new(Mutex::new(recorder)));
+    control::Server::new(recorder.clone(), config_path.to_path_buf()).serve(listener, events);
+    println!(
+        "Monitoring input events (keys). Check logs at: {}",
+        log_path.display()
+    );
 
-            open_accessibility_preferences();
+    if let Err(err) = backend.run(Box::new(health::Timed(Box::new(recorder)))) {
+        log::error!("{}", err);
+        eprintln!("Error: {}. Exiting.", err);
+        process::exit(1);
+    }
+}
 
-            println!("Please grant permissions and restart the app.");
-            log::warn!("App is waiting for permissions. Please restart after granting access.");
-            process::exit(0);
+// simulate plays a script of key events through the config, and prints
+// what whichkey would do with each of them
+fn simulate(config_path: &Path, path: &Path) {
+    let script = match fs::read_to_string(path) {
+        Ok(script) => script,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", path.display(), err);
+            process::exit(1);
         }
+    };
 
-        log::debug!("Attempting to create system-wide accessibility element...");
-        let system_wide = ax_ui_element_create_system_wide();
-        if system_wide.is_null() {
-            log::error!("Failed to create system-wide accessibility element.");
-            eprintln!("Error: Failed to create system-wide accessibility element. Exiting.");
+    match simulate::simulate(config::Config::load(config_path), &script) {
+        Ok(steps) => {
+            for step in steps {
+                println!("{}", step);
+            }
+        }
+        Err(err) => {
+            eprintln!("{}: {}", path.display(), err);
             process::exit(1);
         }
-        log::debug!("Created system-wide accessibility element");
-
-        log::debug!("Setting up CGEventTap for mouse clicks... ");
-        //
-        // Listens to both normal keys and modifier keys
-        let event_mask = (1 << K_CG_EVENT_KEY_DOWN)
-            | (1 << K_CG_EVENT_FLAGS_CHANGED)
-            | (1 << K_CG_EVENT_NX_SYSDEFINED);
-
-        let event_tap = CGEventTapCreate(
-            K_CG_SESSION_EVENT_TAP,
-            K_CG_HEAD_INSERT_EVENT_TAP,
-            K_CG_EVENT_TAP_DEFAULT,
-            event_mask,
-            event_callback,
-            system_wide as *mut c_void,
-        );
-
-        if event_tap.is_null() {
-            log::error!("Failed to create CGEventTap. Ensure Accessibility permissions.");
-            eprintln!("Error: Failed to create CGEventTap. Make sure the process has Accessibility permissions.");
+    }
+}
+
+// control sends the request to the running whichkey, and prints its
+// answer
+fn control(request: control::Request) {
+    match control::request(&control::socket_path(), &request) {
+        Ok(response) => {
+            println!("{}", response);
+            if response["ok"] != true {
+                process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
             process::exit(1);
         }
-        log::debug!("CGEventTap created successfully.");
-
-        let run_loop_source = CFMachPortCreateRunLoopSource(ptr::null_mut(), event_tap, 0);
-        if run_loop_source.is_null() {
-            log::error!("Failed to create RunLoop source for event tap.");
-            eprintln!("Error: Failed to create RunLoop source for event tap.");
-            if !event_tap.is_null() {
-                CFRelease(event_tap as *const c_void);
+    }
+}
+
+// trigger fires the mapping of the keys as if they were typed after the
+// leader key, through the running whichkey if there's one, or else with the
+// config on disk
+fn trigger(config_path: &Path, keys: &str, dry_run: bool) {
+    let socket_path = control::socket_path();
+    if UnixStream::connect(&socket_path).is_ok() {
+        let request = control::Request::Trigger {
+            keys: keys.to_string(),
+            dry_run,
+        };
+        match control::request(&socket_path, &request) {
+            Ok(response) if response["ok"] == true => {
+                let mapping = &response[if dry_run { "mapping" } else { "fired" }];
+                let action = format!(
+                    "{} {}",
+                    mapping["kind"].as_str().unwrap_or_default(),
+                    mapping["command"].as_str().unwrap_or_default()
+                );
+                print_triggered(keys, &action, dry_run);
+            }
+            Ok(response) => {
+                eprintln!("Error: {}", response["error"].as_str().unwrap_or_default());
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
             }
+        }
+        return;
+    }
+
+    let mut recorder = KeyStrokeRecorder::with_config(config::Config::load(config_path));
+    let result = if dry_run {
+        recorder.resolve(keys).cloned()
+    } else {
+        recorder.trigger(keys)
+    };
+    match result {
+        Ok(mapping) => print_triggered(keys, &mapping.action.to_string(), dry_run),
+        Err(err) => {
+            eprintln!("Error: {}", err);
             process::exit(1);
         }
-        log::debug!("RunLoop source created.");
+    }
+}
 
-        let current_run_loop = CFRunLoopGetCurrent();
-        CFRunLoopAddSource(current_run_loop, run_loop_source, kCFRunLoopCommonModes);
-        log::debug!("Event tap source added to run loop.");
+fn print_triggered(keys: &str, action: &str, dry_run: bool) {
+    if dry_run {
+        println!("{:?}: {}", keys, action);
+    } else {
+        println!("fired {:?}: {}", keys, action);
+    }
+}
 
-        CGEventTapEnable(event_tap, true);
-        log::debug!("CGEventTap enabled.");
+// events prints the events of the running whichkey as they happen
+fn events() {
+    let path = control::socket_path();
+    let stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Error: whichkey is not running: {}", err);
+            process::exit(1);
+        }
+    };
 
-        log::info!("Monitoring input events via CGEventTap.");
-        println!("Successfully running with accessibility permissions!");
-        println!(
-            "Monitoring input events (clicks, keys). Check logs at: {}",
-            log_path.display()
-        );
+    let request = serde_json::to_string(&control::Request::SubscribeEvents).unwrap();
+    if let Err(err) = writeln!(&stream, "{}", request) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+        println!("{}", line);
+    }
+}
 
-        log::info!("Starting main run loop...");
-        CFRunLoopRun();
+// check reports what's wrong with the config, one problem per line. It
+// exits with 0 when the config is fine, 1 when it has problems, and 2 when
+// it can't be read.
+fn check(path: &Path) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", path.display(), err);
+            process::exit(2);
+        }
+    };
 
-        log::info!("Run loop finished. Exiting...");
+    let diagnostics = check::check(&content);
+    for diagnostic in diagnostics.iter() {
+        println!("{}:{}", path.display(), diagnostic);
+    }
 
-        CGEventTapEnable(event_tap, false);
-        CFRelease(run_loop_source as *const c_void);
-        CFRelease(event_tap as *const c_void);
+    if diagnostics.iter().any(check::Diagnostic::is_error) {
+        process::exit(1);
     }
+    println!("{}: ok", path.display());
 }
 
-fn install() {
-    match install_config() {
+// list prints the cheat sheet of the config, as text or markdown
+fn list(config_path: &Path, markdown: bool) {
+    let format = if markdown {
+        list::Format::Markdown
+    } else {
+        list::Format::Text
+    };
+
+    print!(
+        "{}",
+        list::cheat_sheet(&config::Config::load(config_path), format)
+    );
+}
+
+// install generates the config and the service, which runs with the same
+// config since launchd doesn't pass the environment of the shell on
+fn install(config_path: &Path) {
+    match install_config(config_path) {
         Ok(()) => {
             println!(
-                "Configuration file generated at $HOME/.config/whichkey/config.toml successfully."
+                "Configuration file generated at {} successfully.",
+                config_path.display()
             );
         }
         Err(e) => {
-            eprintln!("Failed to generate configuration failed at $HOME/.config/whichkey/config.toml. Error: {}", e);
+            eprintln!(
+                "Failed to generate configuration failed at {}. Error: {}",
+                config_path.display(),
+                e
+            );
         }
     }
 
-    match install_service() {
+    match install_service(config_path) {
         Ok(()) => {
             println!("Service installed successfully.");
         }
@@ -239,7 +499,7 @@ fn restart() {
     start();
 }
 
-fn install_service() -> Result<(), Error> {
+fn install_service(config_path: &Path) -> Result<(), Error> {
     let plist = format!(
 "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
 <!DOCTYPE plist PUBLIC \"-//Apple Computer//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">
@@ -251,6 +511,8 @@ fn install_service() -> Result<(), Error> {
     <array>
         <string>{}</string>
         <string>run</string>
+        <string>--config</string>
+        <string>{}</string>
     </array>
     <key>RunAtLoad</key>
     <true/>
@@ -262,14 +524,16 @@ fn install_service() -> Result<(), Error> {
  	     <true/>
     </dict>
     <key>StandardOutPath</key>
-    <string>/tmp/whichkey.out.log</string>
+    <string>/dev/null</string>
     <key>StandardErrorPath</key>
-    <string>/tmp/whichkey.err.log</string>
+    <string>{logs}/whichkey.err.log</string>
     <key>ProcessType</key>
     <string>Interactive</string>
 </dict>
 </plist>",
-        std::env::current_exe().unwrap().to_str().unwrap()
+        std::env::current_exe().unwrap().to_str().unwrap(),
+        config_path.display(),
+        logs = logs_dir().display()
     );
 
     let plist_path = service_path();
@@ -278,22 +542,15 @@ fn install_service() -> Result<(), Error> {
     Ok(())
 }
 
-fn install_config() -> Result<(), Error> {
-    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-    let config_file_path = PathBuf::from(home_dir)
-        .join(".config")
-        .join("whichkey")
-        .join("config.toml");
-
-    if config_file_path.as_path().exists() {
+fn install_config(config_file_path: &Path) -> Result<(), Error> {
+    if config_file_path.exists() {
         return Err(Error::new(
             std::io::ErrorKind::AlreadyExists,
             "Configuration already exists.",
         ));
     }
 
-    let config = format!(
-        "leader_key = \"option\"
+    let config = "leader_key = \"option\"
 
 [[groups]]
 name = \"Open Applications\"
@@ -301,8 +558,7 @@ name = \"Open Applications\"
   [[groups.mappings]]
   keys = \"of\"
   kind = \"Application\"
-  command = \"Finder\""
-    );
+  command = \"Finder\"";
 
     if let Some(parent_dir) = config_file_path.parent() {
         fs::create_dir_all(parent_dir)?;
@@ -312,6 +568,15 @@ name = \"Open Applications\"
 
     Ok(())
 }
+// logs_dir is where launchd keeps what the service prints to stderr, only
+// when it fails to start or crashes since it logs to its own file. It
+// outlives reboots unlike /tmp
+fn logs_dir() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+
+    PathBuf::from(home_dir).join("Library").join("Logs")
+}
+
 fn service_path() -> PathBuf {
     let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
 
@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::check;
+use crate::config;
+
+// How often the config file is checked for changes
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// load reads the config and validates it the way `whichkey check` does,
+// returning every error found if it's not valid. Warnings, like mappings
+// that can never fire, are logged and don't keep the config from loading.
+pub fn load(path: &Path) -> Result<config::Config, Vec<String>> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| vec![format!("Failed to read {}: {}", path.display(), err)])?;
+
+    let (errors, warnings): (Vec<_>, Vec<_>) = check::check(&content)
+        .into_iter()
+        .partition(check::Diagnostic::is_error);
+    if !errors.is_empty() {
+        return Err(errors
+            .iter()
+            .map(|diagnostic| format!("{}:{}", path.display(), diagnostic))
+            .collect());
+    }
+    for warning in warnings {
+        log::warn!("{}:{}", path.display(), warning);
+    }
+
+    toml::from_str(&content).map_err(|err| vec![err.to_string()])
+}
+
+// watch checks the config file every interval, and sends it once it
+// changed and is valid. An invalid config is logged and not sent, so the
+// one in use is kept. Watching stops once the receiver is gone.
+pub fn watch(path: PathBuf, interval: Duration, sender: Sender<config::Config>) {
+    thread::spawn(move || {
+        let mut modified = modified_at(&path);
+        loop {
+            thread::sleep(interval);
+
+            let now = modified_at(&path);
+            if now == modified {
+                continue;
+            }
+            modified = now;
+
+            match load(&path) {
+                Ok(config) => {
+                    log::info!("Reloading config {}", path.display());
+                    if sender.send(config).is_err() {
+                        return;
+                    }
+                }
+                Err(errors) => {
+                    log::error!("Keeping the config in use, {} is invalid:", path.display());
+                    for error in errors {
+                        log::error!("{}", error);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// modified_at is when the file was last written, None while it's missing,
+// e.g. between an editor removing it and writing it back
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const CONFIG: &str = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+"#;
+
+    #[test]
+    fn load_reports_every_problem() {
+        let path = std::env::temp_dir().join(format!("whichkey-load-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "{}{}",
+                CONFIG,
+                CONFIG.replace("leader_key = \"option\"", "")
+            ),
+        )
+        .unwrap();
+
+        let errors = load(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("already mapped"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn load_accepts_mapping_begun_by_a_shorter_one() {
+        let path =
+            std::env::temp_dir().join(format!("whichkey-load-prefix-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "{}{}",
+                CONFIG.replace("\"of\"", "\"o\""),
+                CONFIG
+                    .replace("leader_key = \"option\"", "")
+                    .replace("\"of\"", "\"ovs\"")
+            ),
+        )
+        .unwrap();
+
+        let config = load(&path);
+        fs::remove_file(&path).unwrap();
+        let keys: Vec<_> = config
+            .unwrap()
+            .groups
+            .iter()
+            .map(|group| group.mappings[0].keys.clone())
+            .collect();
+        assert_eq!(keys, ["o", "ovs"]);
+    }
+
+    #[test]
+    fn watch_sends_changed_valid_config() {
+        let path = std::env::temp_dir().join(format!("whichkey-watch-{}.toml", std::process::id()));
+        fs::write(&path, CONFIG).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let interval = Duration::from_millis(10);
+        watch(path.clone(), interval, sender);
+
+        // File times can be coarse, wait for them to tell the writes apart
+        thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "leader_key = ").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err());
+
+        fs::write(&path, CONFIG.replace("Finder", "Safari")).unwrap();
+        let config = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.groups[0].mappings[0].action.command(), "Safari");
+    }
+}
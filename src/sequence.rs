@@ -1,52 +1,231 @@
-use nix::libc::_exit;
-use nix::unistd::{execvp, fork, getpid, setsid, ForkResult};
-use std::ffi::CString;
-use std::process::Command;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, Sender};
 use std::time::{Duration, Instant};
 
+use crate::clock::{Clock, SystemClock};
 use crate::config;
-
-const KEY_STROKE_INTERVAL: u64 = 1000;
-const NUM_LEADER_KEY_STROKES: usize = 3;
-
-// refer to https://docs.rs/objc2-core-graphics/latest/src/objc2_core_graphics/generated/CGEventTypes.rs.html#171
-pub const K_CG_EVENT_FLAG_MASK_OPTION_DOWN: u64 = 524576;
-pub const K_CG_EVENT_FLAG_MASK_OPTION_UP: u64 = 256;
-
-pub const K_CG_EVENT_FLAG_MASK_COMMAND_DOWN: u64 = 1048840;
-pub const K_CG_EVENT_FLAG_MASK_COMMAND_UP: u64 = 256;
-
-pub const K_CG_EVENT_FLAG_MASK_CONTROL_DOWN: u64 = 262401;
-pub const K_CG_EVENT_FLAG_MASK_CONTROL_UP: u64 = 256;
-
-pub const K_CG_EVENT_FLAG_MASK_SHIFT_DOWN: u64 = 131330;
-pub const K_CG_EVENT_FLAG_MASK_SHIFT_UP: u64 = 256;
+use crate::executor;
+use crate::hint::{Hint, HintDisplay};
+use crate::input::{KeyEvent, KeyEventHandler, KeyState, Modifiers, Verdict, MOUSE_CLICK};
+use crate::keys;
 
 pub struct KeyStrokeRecorder {
-    pub strokes: Vec<KeyStroke>,
+    pub strokes: Vec<KeyEvent>,
     pub last_stroke_timestamp: Instant,
     config: config::Config,
+    // The token of the leader key when activated as a chord
+    chord: Option<String>,
+    trie: SequenceTrie,
+    clock: Box<dyn Clock>,
+    on_fire: Box<dyn FnMut(&config::Mapping) + Send>,
+    display: Option<Box<dyn HintDisplay>>,
+    showing_hints: bool,
+    reloads: Option<Receiver<config::Config>>,
+    // A reloaded config waiting for the sequence under way to be over
+    next_config: Option<config::Config>,
+    paused: bool,
+    events: Option<Sender<Event>>,
+}
+
+// Event is something the recorder did, reported to the subscribers of the
+// control socket
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // A mapping fired, typed or triggered
+    Fired(config::Mapping),
+    // The keys typed after the leader key can't match any mapping
+    Aborted(Vec<String>),
+    // A new config is in use
+    Reloaded,
+    Paused,
+    Resumed,
+}
+
+// Match is the result of looking up the keys typed after the leader key
+#[derive(Debug, PartialEq)]
+pub enum Match<'a> {
+    // No mapping starts with the keys, the sequence should be aborted
+    None,
+    // The keys are the beginning of at least one mapping, wait for more
+    Prefix,
+    // The keys are a complete mapping
+    Exact(&'a config::Mapping),
 }
 
-#[derive(Debug)]
-pub struct KeyStroke {
-    pub key_code: i64,
-    // pub key_typ: u32,
-    pub flag: u64,
-    // pub timestamp: Instant,
+// SequenceTrie is a prefix tree of all the mappings in the config, keyed
+// by keys::token. It's built once when the config is loaded, so a lookup only
+// walks as many nodes as keys typed, no matter how many mappings there are.
+#[derive(Default)]
+pub struct SequenceTrie {
+    root: TrieNode,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: HashMap<String, TrieNode>,
+    mapping: Option<config::Mapping>,
+    // Tightest timeout of the mappings under this node
+    timeout: Option<Duration>,
+    // Names of the groups of the mappings under this node
+    groups: Vec<String>,
+    // Label of the prefix from the config, if any
+    label: Option<String>,
 }
 
 impl KeyStrokeRecorder {
-    pub fn new() -> Self {
-        let c = config::Config::new();
+    pub fn with_config(c: config::Config) -> Self {
         KeyStrokeRecorder {
             strokes: vec![],
             last_stroke_timestamp: Instant::now(),
+            trie: SequenceTrie::new(&c),
+            chord: chord(&c),
             config: c,
+            clock: Box::new(SystemClock),
+            on_fire: Box::new(Self::run_mapping),
+            display: None,
+            showing_hints: false,
+            reloads: None,
+            next_config: None,
+            paused: false,
+            events: None,
+        }
+    }
+
+    // with_events reports what the recorder does to the sender
+    pub fn with_events(mut self, events: Sender<Event>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = self.events.as_ref() {
+            let _ = events.send(event);
+        }
+    }
+
+    pub fn config(&self) -> &config::Config {
+        &self.config
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // set_paused lets every key through while paused, a sequence under way
+    // is dropped
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused == paused {
+            return;
         }
+
+        self.paused = paused;
+        self.strokes.clear();
+        self.update_hints();
+        self.emit(if paused {
+            Event::Paused
+        } else {
+            Event::Resumed
+        });
     }
 
-    pub fn record(&mut self, key_stroke: KeyStroke) {
+    // reload_with swaps the config in, or once the sequence under way is
+    // over
+    pub fn reload_with(&mut self, config: config::Config) {
+        self.next_config = Some(config);
+        self.reload();
+    }
+
+    // resolve finds the mapping the keys fire when typed after the leader
+    // key, it fails unless they're a complete mapping. Like when typed, a
+    // shorter mapping they begin with fires first.
+    pub fn resolve(&self, keys: &str) -> Result<&config::Mapping, String> {
+        let keys = keys::parse(keys)?;
+        for end in 1..=keys.len() {
+            match self.trie.lookup(&keys[..end]) {
+                Match::Exact(mapping) if end == keys.len() => return Ok(mapping),
+                Match::Exact(mapping) => {
+                    return Err(format!(
+                        "{:?} fires {:?} first",
+                        keys.concat(),
+                        mapping.keys
+                    ))
+                }
+                Match::Prefix => continue,
+                Match::None => break,
+            }
+        }
+
+        if keys.is_empty() || self.trie.lookup(&keys) == Match::None {
+            Err(format!("No mapping for {:?}", keys.concat()))
+        } else {
+            Err(format!(
+                "{:?} is only the beginning of mappings",
+                keys.concat()
+            ))
+        }
+    }
+
+    // trigger fires the mapping of the keys as if they were typed after the
+    // leader key
+    pub fn trigger(&mut self, keys: &str) -> Result<config::Mapping, String> {
+        let mapping = self.resolve(keys)?.clone();
+        (self.on_fire)(&mapping);
+        self.emit(Event::Fired(mapping.clone()));
+        Ok(mapping)
+    }
+
+    // with_reloads swaps the config for the ones received, in between
+    // sequences so a sequence never mixes the mappings of two configs
+    pub fn with_reloads(mut self, reloads: Receiver<config::Config>) -> Self {
+        self.reloads = Some(reloads);
+        self
+    }
+
+    // reload takes the latest config received, and swaps it in unless a
+    // sequence is under way
+    fn reload(&mut self) {
+        if let Some(reloads) = self.reloads.as_ref() {
+            if let Some(config) = reloads.try_iter().last() {
+                self.next_config = Some(config);
+            }
+        }
+
+        if self.next_config.is_none() || self.is_pending() {
+            return;
+        }
+
+        if let Some(config) = self.next_config.take() {
+            self.trie = SequenceTrie::new(&config);
+            self.chord = chord(&config);
+            self.config = config;
+            self.emit(Event::Reloaded);
+        }
+    }
+
+    // with_display shows the keys that can come next on the display, after
+    // the leader key and each prefix
+    pub fn with_display(mut self, display: Box<dyn HintDisplay>) -> Self {
+        self.display = Some(display);
+        self
+    }
+
+    // with_clock replaces the real time the recorder uses, key strokes are
+    // still timed by their own timestamps
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.last_stroke_timestamp = clock.now();
+        self.clock = Box::new(clock);
+        self
+    }
+
+    // on_fire replaces what happens when a mapping fires, running its
+    // command by default. The simulator uses it to report the mappings
+    // instead of running them.
+    pub fn on_fire(mut self, f: impl FnMut(&config::Mapping) + Send + 'static) -> Self {
+        self.on_fire = Box::new(f);
+        self
+    }
+
+    pub fn record(&mut self, key_stroke: KeyEvent) {
         // If key stroke timestamp is within the threshold, record it
         // otherwise reset the strokes
         // First check if leader(modifier) key
@@ -54,269 +233,1488 @@ impl KeyStrokeRecorder {
         //
         // If leader key is hit, don't forward the key strokes
 
-        let elapsed = self.last_stroke_timestamp.elapsed();
-        if elapsed <= Duration::from_millis(KEY_STROKE_INTERVAL) {
-            // println!("====within {}, key: {:?}", KEY_STROKE_INTERVAL, key_stroke);
+        let timestamp = key_stroke.timestamp;
+        let elapsed = timestamp.saturating_duration_since(self.last_stroke_timestamp);
+        let timeout = self.timeout();
+        // A leader key held waits for the first key as long as it's held
+        let waiting = self.is_leader_held() && self.is_leader_typed();
+        // The leader key starts over, unless it's typed within a sequence
+        // or it's the second tap of a double tap
+        let restart = self.is_leader(&key_stroke)
+            && key_stroke.state == KeyState::Down
+            && !self.is_in_sequence()
+            && !self.awaits_second_tap();
+        if (elapsed <= timeout || waiting) && !restart {
             self.strokes.push(key_stroke);
         } else {
             self.strokes = vec![key_stroke];
         }
 
-        self.last_stroke_timestamp = Instant::now();
+        self.last_stroke_timestamp = timestamp;
     }
 
-    // is_in_sequence checks if leader key is down and up
-    // it needs to check the first two key strokes, firts is down, second is up
-    pub fn is_in_sequence(&self) -> bool {
-        if self.strokes.len() < NUM_LEADER_KEY_STROKES {
-            return false;
+    // timeout is how long the sequence waits for the next key: the tightest
+    // timeout of the mappings the keys typed so far can still lead to
+    fn timeout(&self) -> Duration {
+        self.trie
+            .timeout(&self.sequence())
+            .unwrap_or(Duration::from_millis(self.config.timeout_ms))
+    }
+
+    // sequence returns the keys typed after the leader key, named with the
+    // modifiers they were typed with
+    fn sequence(&self) -> Vec<String> {
+        let Some(leader_strokes) = self.leader_strokes() else {
+            return vec![];
+        };
+        if !self.is_in_sequence() {
+            return vec![];
         }
 
-        let third_key = &self.strokes[2];
-        if Self::key_code_to_name(third_key.key_code) == self.config.leader_key {
-            return false;
+        self.strokes[leader_strokes..]
+            .iter()
+            .map(|stroke| keys::token(&stroke.key, self.without_leader(stroke.modifiers)))
+            .collect()
+    }
+
+    // without_leader drops the leader key from the modifiers the keys were
+    // typed with, a leader modifier is held all along when activated by
+    // holding it
+    fn without_leader(&self, mut modifiers: Modifiers) -> Modifiers {
+        if self.config.leader_activation != config::LeaderActivation::Hold {
+            return modifiers;
         }
 
-        let first_key = &self.strokes[0];
-        let second_key = &self.strokes[1];
+        match self.config.leader_key.trim_start_matches("right") {
+            "shift" => modifiers.shift = false,
+            "control" => modifiers.control = false,
+            "option" => modifiers.option = false,
+            "command" => modifiers.command = false,
+            _ => {}
+        }
+        modifiers
+    }
+
+    // is_leader checks if the key event is the leader key, or the key of
+    // the chord with its modifiers on its way down
+    fn is_leader(&self, key_event: &KeyEvent) -> bool {
+        if self.config.leader_activation == config::LeaderActivation::Chord {
+            return key_event.state == KeyState::Down
+                && self.chord.as_ref().is_some_and(|chord| {
+                    keys::token(&key_event.key, key_event.modifiers) == *chord
+                });
+        }
 
-        Self::key_code_to_name(first_key.key_code) == self.config.leader_key
-            && Self::key_code_to_name(second_key.key_code) == self.config.leader_key
-            && Self::leader_key_down(first_key.key_code, first_key.flag)
-            && Self::leader_key_up(second_key.key_code, second_key.flag)
+        key_event.key == self.config.leader_key
     }
 
-    pub fn check_sequence(&mut self) {
-        if self.strokes.len() < NUM_LEADER_KEY_STROKES {
-            return;
+    // is_tap checks if the leader key went down and up quick enough to be
+    // a tap of the leader_activation
+    fn is_tap(&self, down: &KeyEvent, up: &KeyEvent) -> bool {
+        let tap = Duration::from_millis(self.config.leader_tap_ms);
+        self.is_leader(down)
+            && self.is_leader(up)
+            && down.state == KeyState::Down
+            && up.state == KeyState::Up
+            && up.timestamp.saturating_duration_since(down.timestamp) <= tap
+    }
+
+    // awaits_second_tap checks if the leader key was tapped once, and a
+    // double tap needs it tapped again
+    fn awaits_second_tap(&self) -> bool {
+        match self.strokes.as_slice() {
+            [down, up] => {
+                self.config.leader_activation == config::LeaderActivation::DoubleTap
+                    && self.is_tap(down, up)
+            }
+            _ => false,
         }
+    }
 
-        // Retrieve key strokes and match the pattern
-        // let mut sequence  = Vec::new();
-        let seq: Vec<_> = self.strokes.iter().map(|stroke| stroke.key_code).collect();
+    // leader_strokes returns how many of the first strokes activated the
+    // leader key, if they did:
+    //
+    // - tap: the leader key down and up, with no other key in between
+    // - double_tap: two taps, the second one soon after the first one
+    // - hold: the leader key down, it's still held
+    // - chord: the key of the chord with its modifiers
+    fn leader_strokes(&self) -> Option<usize> {
+        let tap = Duration::from_millis(self.config.leader_tap_ms);
+        match (self.config.leader_activation, self.strokes.as_slice()) {
+            (config::LeaderActivation::Tap, [down, up, ..]) if self.is_tap(down, up) => Some(2),
+            (config::LeaderActivation::DoubleTap, [down, up, second_down, second_up, ..])
+                if self.is_tap(down, up)
+                    && self.is_tap(second_down, second_up)
+                    && second_down
+                        .timestamp
+                        .saturating_duration_since(up.timestamp)
+                        <= tap =>
+            {
+                Some(4)
+            }
+            (config::LeaderActivation::Hold, [down, ..])
+                if self.is_leader(down) && down.state == KeyState::Down =>
+            {
+                Some(1)
+            }
+            (config::LeaderActivation::Chord, [chord, ..]) if self.is_leader(chord) => Some(1),
+            _ => None,
+        }
+    }
 
-        let seq_array: &[i64] = &seq;
-        // println!("===seq array: {:?}", seq_array);
+    // is_leader_typed checks if the leader key was just activated, and the
+    // sequence waits for its first key
+    fn is_leader_typed(&self) -> bool {
+        self.leader_strokes() == Some(self.strokes.len())
+    }
 
-        let key_sequence = seq_array[2..]
-            .iter()
-            .map(|seq| Self::key_code_to_name(*seq).to_string())
-            .collect::<Vec<String>>()
-            .join("");
+    // is_leader_held checks if the leader key activated by holding it is
+    // still held
+    fn is_leader_held(&self) -> bool {
+        self.config.leader_activation == config::LeaderActivation::Hold
+            && self.leader_strokes().is_some()
+    }
 
-        // println!("====key seq: {}", key_sequence);
+    // is_in_sequence checks if the leader key was activated and followed by
+    // another key
+    pub fn is_in_sequence(&self) -> bool {
+        match self.leader_strokes() {
+            Some(leader_strokes) => self
+                .strokes
+                .get(leader_strokes)
+                .is_some_and(|key| !self.is_leader(key)),
+            None => false,
+        }
+    }
 
-        for group in self.config.groups.iter() {
-            for mapping in group.mappings.iter() {
-                if mapping.keys == key_sequence {
-                    // println!("===mapping found {:?}", mapping);
-                    match mapping.kind.as_str() {
-                        "Application" => {
-                            // Self::fork_and_exec(&mapping.command);
-                            let cmd_result =
-                                Command::new("open").arg("-a").arg(&mapping.command).spawn();
-
-                            match cmd_result {
-                                Ok(child) => {
-                                    log::info!("App launched successfully (pid: {}).", child.id());
-                                }
-                                Err(err) => {
-                                    log::error!("Failed to open App : {}", err);
-                                }
-                            }
-                        }
-                        "Command" => {
-                            let cmd_result =
-                                Command::new("sh").arg("-c").arg(&mapping.command).spawn();
-
-                            match cmd_result {
-                                Ok(child) => {
-                                    log::info!("Command ran successfully (pid: {}).", child.id());
-                                }
-                                Err(err) => {
-                                    log::error!("Failed to run command: {}", err);
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
+    // check_sequence matches the keys typed after the leader key, it fires
+    // the mapping on an exact match and aborts the sequence as soon as no
+    // mapping can match anymore
+    pub fn check_sequence(&mut self) -> Verdict {
+        if !self.is_in_sequence() {
+            return Verdict::Pass;
+        }
+
+        // Retrieve key strokes and match the pattern
+        let keys = self.sequence();
 
+        match self.trie.lookup(&keys) {
+            Match::Exact(mapping) => {
+                (self.on_fire)(mapping);
+                self.emit(Event::Fired(mapping.clone()));
+                // Still held, the leader key goes on with another sequence
+                if self.is_leader_held() {
+                    self.strokes.truncate(1);
+                } else {
                     self.strokes.clear();
                 }
+                Verdict::Consume
             }
+            Match::Prefix => Verdict::Consume,
+            // Nothing can match anymore, abort the sequence
+            Match::None => self.abort(),
         }
     }
 
-    // key code mapping:
-    // https://github.com/caseyscarborough/keylogger/blob/master/keylogger.c#L117
-    // shift key pressing is not considered at the moment
-    fn key_code_to_name(code: i64) -> &'static str {
-        match code {
-            0 => "a",
-            1 => "s",
-            2 => "d",
-            3 => "f",
-            4 => "h",
-            5 => "g",
-            6 => "z",
-            7 => "x",
-            8 => "c",
-            9 => "v",
-            11 => "b",
-            12 => "q",
-            13 => "w",
-            14 => "e",
-            15 => "r",
-            16 => "y",
-            17 => "t",
-            18 => "1",
-            19 => "2",
-            20 => "3",
-            21 => "4",
-            22 => "6",
-            23 => "5",
-            24 => "=",
-            25 => "9",
-            26 => "7",
-            27 => "-",
-            28 => "8",
-            29 => "0",
-            30 => "]",
-            31 => "o",
-            32 => "u",
-            33 => "[",
-            34 => "i",
-            35 => "p",
-            36 => "return",
-            37 => "l",
-            38 => "j",
-            39 => "'",
-            40 => "k",
-            41 => ";",
-            42 => "\\",
-            43 => ",",
-            44 => "/",
-            45 => "n",
-            46 => "m",
-            47 => ".",
-            48 => "tab",
-            49 => "space",
-            50 => "`",
-            51 => "delete",
-            53 => "escape",
-            55 => "command",
-            56 => "shift",
-            57 => "capslock",
-            58 => "option",
-            59 => "control",
-            60 => "rightshift",
-            61 => "rightoption",
-            62 => "rightcontrol",
-            63 => "fn",
-            64 => "f17",
-            65 => "keypad.",
-            67 => "keypad*",
-            69 => "keypad+",
-            71 => "keypadclear",
-            75 => "keypad/",
-            76 => "keypadenter",
-            78 => "keypad-",
-            79 => "f18",
-            80 => "f19",
-            81 => "keypad=",
-            82 => "keypad0",
-            83 => "keypad1",
-            84 => "keypad2",
-            85 => "keypad3",
-            86 => "keypad4",
-            87 => "keypad5",
-            88 => "keypad6",
-            89 => "keypad7",
-            90 => "f20",
-            91 => "keypad8",
-            92 => "keypad9",
-            96 => "f5",
-            97 => "f6",
-            98 => "f7",
-            99 => "f3",
-            100 => "f8",
-            101 => "f9",
-            103 => "f11",
-            105 => "f13",
-            106 => "f16",
-            107 => "f14",
-            109 => "f10",
-            111 => "f12",
-            113 => "f15",
-            114 => "help",
-            115 => "home",
-            116 => "pageup",
-            117 => "forwarddelete",
-            118 => "f4",
-            119 => "end",
-            120 => "f2",
-            121 => "pagedown",
-            122 => "f1",
-            123 => "left",
-            124 => "right",
-            125 => "down",
-            126 => "up",
-            _ => "unknown",
-        }
-    }
-
-    fn leader_key_up(code: i64, flag: u64) -> bool {
-        match code {
-            55 => flag & K_CG_EVENT_FLAG_MASK_COMMAND_UP > 0,
-            56 => flag & K_CG_EVENT_FLAG_MASK_SHIFT_UP > 0,
-            58 => flag & K_CG_EVENT_FLAG_MASK_OPTION_UP > 0,
-            59 => flag & K_CG_EVENT_FLAG_MASK_CONTROL_UP > 0,
-            _ => false,
+    // abort ends the sequence under way, its keys are given back or thrown
+    // away depending on on_abort
+    fn abort(&mut self) -> Verdict {
+        let keys = self.sequence();
+        log::debug!("No mapping for sequence {:?}, aborting", keys);
+        self.emit(Event::Aborted(keys));
+        let swallowed = match self.leader_strokes() {
+            Some(leader_strokes) => self.strokes.split_off(leader_strokes),
+            None => vec![],
+        };
+        self.strokes.clear();
+
+        match self.config.on_abort {
+            config::AbortAction::Drop => Verdict::Consume,
+            config::AbortAction::Replay => Verdict::Replay(swallowed),
         }
     }
 
-    fn leader_key_down(code: i64, flag: u64) -> bool {
-        match code {
-            55 => flag & K_CG_EVENT_FLAG_MASK_COMMAND_DOWN > 0,
-            56 => flag & K_CG_EVENT_FLAG_MASK_SHIFT_DOWN > 0,
-            58 => flag & K_CG_EVENT_FLAG_MASK_OPTION_DOWN > 0,
-            59 => flag & K_CG_EVENT_FLAG_MASK_CONTROL_DOWN > 0,
-            _ => false,
+    // release_leader ends the sequence of the leader key activated by
+    // holding it, keys that didn't fire a mapping yet are aborted. A leader
+    // modifier went down on its way to the application, it goes up too.
+    fn release_leader(&mut self, key_event: &KeyEvent) -> Verdict {
+        let verdict = if self.is_in_sequence() {
+            self.abort()
+        } else {
+            Verdict::Pass
+        };
+        self.strokes.clear();
+
+        match verdict {
+            Verdict::Replay(mut events) if keys::is_modifier(&key_event.key) => {
+                events.push(key_event.clone());
+                Verdict::Replay(events)
+            }
+            Verdict::Consume if keys::is_modifier(&key_event.key) => Verdict::Pass,
+            verdict => verdict,
+        }
+    }
+
+    // swallow_leader keeps the leader key from the application when it's
+    // only typed to start a sequence, like "f18" or the space of
+    // "<C-space>". Modifiers go on to be held with other keys.
+    fn swallow_leader(&self, key_event: &KeyEvent, verdict: Verdict) -> Verdict {
+        let swallowed = self.is_leader(key_event)
+            && (self.config.leader_activation == config::LeaderActivation::Chord
+                || !keys::is_modifier(&key_event.key));
+        match verdict {
+            Verdict::Pass if swallowed => Verdict::Consume,
+            verdict => verdict,
         }
     }
 
-    #[allow(dead_code)]
-    fn fork_and_exec(command: &String) {
-        // First fork
-        match unsafe { fork() }.expect("First fork failed") {
-            ForkResult::Parent { child } => {
-                println!("Parent continuing, first child pid = {}", child);
-                // Parent returns immediately, doesn't exit
-                return;
+    // update_hints shows the keys that can come next while the sequence
+    // waits for one, and hides them once it's over
+    fn update_hints(&mut self) {
+        if self.display.is_none() {
+            return;
+        }
+
+        let keys = self.sequence();
+        if keys.is_empty() && !self.is_leader_typed() {
+            if self.showing_hints {
+                if let Some(display) = self.display.as_mut() {
+                    display.hide();
+                }
+                self.showing_hints = false;
             }
-            ForkResult::Child => {
-                // First child continues
+            return;
+        }
+
+        let hints = self.trie.hints(&keys);
+        let timeout = self.timeout();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        if let Some(display) = self.display.as_mut() {
+            display.show(&keys, &hints, timeout);
+        }
+        self.showing_hints = true;
+    }
+
+    // route records the key event and decides what happens to it
+    fn route(&mut self, key_event: &KeyEvent) -> Verdict {
+        self.reload();
+        if self.paused {
+            return Verdict::Pass;
+        }
+
+        // A click isn't a key of the sequence, it breaks a tap of the
+        // leader key and ends the sequence under way
+        if key_event.key == MOUSE_CLICK {
+            let verdict = if self.is_in_sequence() {
+                self.abort()
+            } else {
+                Verdict::Pass
+            };
+            self.strokes.clear();
+            self.update_hints();
+            return verdict;
+        }
+
+        // While the leader key is held, its repeats aren't keys of the
+        // sequence, and releasing it ends the sequence
+        if self.is_leader_held() && key_event.key == self.config.leader_key {
+            let verdict = match key_event.state {
+                KeyState::Down => Verdict::Pass,
+                KeyState::Up => self.release_leader(key_event),
+            };
+            self.update_hints();
+            return self.swallow_leader(key_event, verdict);
+        }
+
+        // Modifiers only change the keys they're held with, they aren't
+        // keys of the sequence. Right after the leader key, the leader key
+        // itself still starts over.
+        if keys::is_modifier(&key_event.key)
+            && (self.is_in_sequence()
+                || self.is_leader_typed() && key_event.key != self.config.leader_key)
+        {
+            return Verdict::Pass;
+        }
+
+        // Keys are followed by their key down, only the leader key is
+        // followed on its way up too
+        if key_event.state == KeyState::Up && key_event.key != self.config.leader_key {
+            return Verdict::Pass;
+        }
+
+        self.record(key_event.clone());
+        let verdict = if self.is_in_sequence() {
+            self.check_sequence()
+        } else {
+            Verdict::Pass
+        };
+
+        self.update_hints();
+        self.swallow_leader(key_event, verdict)
+    }
+
+    // run_mapping starts the action right away, and leaves its process for
+    // the system to reap once whichkey exits. The daemon goes through an
+    // executor::Executor instead.
+    fn run_mapping(mapping: &config::Mapping) {
+        let _ = executor::run(&mapping.action);
+    }
+}
+
+// chord returns the token of the leader key activated as a chord, like
+// "<C-space>"
+fn chord(config: &config::Config) -> Option<String> {
+    if config.leader_activation != config::LeaderActivation::Chord {
+        return None;
+    }
+
+    match keys::parse(&config.leader_key) {
+        Ok(tokens) if tokens.len() == 1 => tokens.into_iter().next(),
+        _ => {
+            log::warn!(
+                "Leader key {:?} is not a chord like \"<C-space>\", it can't start a sequence",
+                config.leader_key
+            );
+            None
+        }
+    }
+}
+
+impl KeyEventHandler for KeyStrokeRecorder {
+    // handle records the key event and decides what happens to it. Only the
+    // key events of a sequence under way are logged, unless debug_keys is
+    // on.
+    fn handle(&mut self, key_event: &KeyEvent) -> Verdict {
+        let was_pending = self.is_pending();
+        let verdict = self.route(key_event);
+        if self.config.logging.debug_keys || was_pending || self.is_pending() {
+            log::debug!("Key {} {:?}: {:?}", key_event.key, key_event.state, verdict);
+        }
+
+        verdict
+    }
+
+    // is_pending tells if the leader key was hit and the sequence is still
+    // waiting for keys
+    fn is_pending(&self) -> bool {
+        let elapsed = self
+            .clock
+            .now()
+            .saturating_duration_since(self.last_stroke_timestamp);
+        if elapsed > self.timeout() && !(self.is_leader_held() && self.is_leader_typed()) {
+            return false;
+        }
+
+        self.is_leader_typed() || self.is_in_sequence()
+    }
+}
+
+impl TrieNode {
+    fn tighten(&mut self, timeout: Duration) {
+        self.timeout = Some(self.timeout.map_or(timeout, |t| t.min(timeout)));
+    }
+
+    fn add_group(&mut self, group: &str) {
+        if !self.groups.iter().any(|g| g == group) {
+            self.groups.push(group.to_string());
+        }
+    }
+}
+
+impl SequenceTrie {
+    pub fn new(config: &config::Config) -> Self {
+        let mut trie = SequenceTrie::default();
+        for group in config.groups.iter() {
+            for mapping in group.mappings.iter() {
+                let keys = match keys::parse(&mapping.keys) {
+                    Ok(keys) => keys,
+                    Err(err) => {
+                        log::warn!("Skipping the mapping of {:?}: {}", mapping.keys, err);
+                        continue;
+                    }
+                };
+                let timeout_ms = mapping
+                    .timeout_ms
+                    .or(group.timeout_ms)
+                    .unwrap_or(config.timeout_ms);
+                trie.insert(
+                    mapping,
+                    &keys,
+                    &group.name,
+                    Duration::from_millis(timeout_ms),
+                );
             }
         }
 
-        // Detach from terminal/session
-        setsid().expect("setsid failed");
+        for (keys, label) in config.prefixes.iter() {
+            let Ok(keys) = keys::parse(keys) else {
+                continue;
+            };
+            if let Some(node) = trie.lookup_node_mut(&keys) {
+                node.label = Some(label.clone());
+            }
+        }
+
+        trie
+    }
+
+    // insert adds the mapping under its keys, one node per key, and
+    // tightens the timeout and adds the group of the nodes on the way. If
+    // two mappings share the same keys, the first one wins.
+    fn insert(
+        &mut self,
+        mapping: &config::Mapping,
+        keys: &[String],
+        group: &str,
+        timeout: Duration,
+    ) {
+        if self
+            .lookup_node(keys)
+            .is_some_and(|node| node.mapping.is_some())
+        {
+            return;
+        }
+
+        let mut node = &mut self.root;
+        node.tighten(timeout);
+        node.add_group(group);
+        for key in keys {
+            node = node.children.entry(key.clone()).or_default();
+            node.tighten(timeout);
+            node.add_group(group);
+        }
+
+        node.mapping = Some(mapping.clone());
+    }
 
-        // Second fork
-        match unsafe { fork() }.expect("Second fork failed") {
-            ForkResult::Parent { child } => {
-                // First child exits, leaving grandchild running
-                println!("First child exiting, grandchild pid = {}", child);
-                unsafe { _exit(0) }; // Use _exit instead of std::process::exit
+    fn lookup_node_mut(&mut self, keys: &[String]) -> Option<&mut TrieNode> {
+        let mut node = &mut self.root;
+        for key in keys {
+            node = node.children.get_mut(key)?;
+        }
+
+        Some(node)
+    }
+
+    fn lookup_node(&self, keys: &[String]) -> Option<&TrieNode> {
+        let mut node = &self.root;
+        for key in keys {
+            node = node.children.get(key)?;
+        }
+
+        Some(node)
+    }
+
+    // timeout returns the tightest timeout of the mappings the keys can
+    // still lead to, if any
+    pub fn timeout<S: AsRef<str>>(&self, keys: &[S]) -> Option<Duration> {
+        let mut node = &self.root;
+        for key in keys {
+            node = node.children.get(key.as_ref())?;
+        }
+
+        node.timeout
+    }
+
+    // hints lists the keys that can come next after the keys typed so far,
+    // sorted by key. A key is labeled with the description of its mapping,
+    // or if it's a prefix, its label from the config or the groups it leads
+    // to.
+    pub fn hints<S: AsRef<str>>(&self, keys: &[S]) -> Vec<Hint> {
+        let mut node = &self.root;
+        for key in keys {
+            match node.children.get(key.as_ref()) {
+                Some(child) => node = child,
+                None => return vec![],
             }
-            ForkResult::Child => {
-                // Grandchild is now fully detached
-                println!("Daemon process running, pid = {}", getpid());
-                let cmd = CString::new("open").unwrap();
-                let arg1 = CString::new("-a").unwrap();
-                let arg2 = CString::new(command.as_str()).unwrap();
-                execvp(&cmd, &[cmd.clone(), arg1, arg2]).expect("execvp failed");
+        }
+
+        let mut hints: Vec<Hint> = node
+            .children
+            .iter()
+            .map(|(key, child)| match &child.mapping {
+                Some(mapping) => Hint {
+                    key: key.clone(),
+                    label: mapping.label().to_string(),
+                    is_prefix: false,
+                },
+                None => Hint {
+                    key: key.clone(),
+                    label: child
+                        .label
+                        .clone()
+                        .unwrap_or_else(|| child.groups.join(", ")),
+                    is_prefix: true,
+                },
+            })
+            .collect();
+        hints.sort_by(|a, b| a.key.cmp(&b.key));
+
+        hints
+    }
+
+    // lookup walks the trie with the keys typed so far. A mapping fires as
+    // soon as its keys are typed, so for "o" and "ovs", "o" always wins.
+    pub fn lookup<S: AsRef<str>>(&self, keys: &[S]) -> Match<'_> {
+        let mut node = &self.root;
+        for key in keys {
+            match node.children.get(key.as_ref()) {
+                Some(child) => node = child,
+                None => return Match::None,
             }
         }
+
+        match &node.mapping {
+            Some(mapping) => Match::Exact(mapping),
+            None if node.children.is_empty() => Match::None,
+            None => Match::Prefix,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::input::Modifiers;
+
+    fn trie(content: &str) -> SequenceTrie {
+        let config: config::Config = toml::from_str(content).unwrap();
+        SequenceTrie::new(&config)
+    }
+
+    const CONFIG: &str = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "ovs"
+  kind = "Application"
+  command = "Visual Studio Code"
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "rt"
+  kind = "Command"
+  command = "true"
+"#;
+
+    fn key(name: &str, state: KeyState) -> KeyEvent {
+        KeyEvent {
+            key: name.to_string(),
+            modifiers: Modifiers::default(),
+            state,
+            timestamp: Instant::now(),
+        }
+    }
+
+    fn stroke(name: &str) -> KeyEvent {
+        key(name, KeyState::Down)
+    }
+
+    fn leader() -> [KeyEvent; 2] {
+        [key("option", KeyState::Down), key("option", KeyState::Up)]
+    }
+
+    // at returns the key event stamped with the time of the clock
+    fn at(clock: &ManualClock, name: &str, state: KeyState) -> KeyEvent {
+        KeyEvent {
+            timestamp: clock.now(),
+            ..key(name, state)
+        }
+    }
+
+    // recorder is the recorder of the config, its mappings don't run
+    // anything
+    fn recorder(content: &str) -> KeyStrokeRecorder {
+        KeyStrokeRecorder::with_config(toml::from_str(content).unwrap()).on_fire(|_| {})
+    }
+
+    // firing is the recorder of the config, with the keys of the mappings
+    // it fires sent to the receiver
+    fn firing(content: &str) -> (KeyStrokeRecorder, std::sync::mpsc::Receiver<String>) {
+        let (sender, fired) = std::sync::mpsc::channel();
+        let recorder =
+            recorder(content).on_fire(move |mapping| sender.send(mapping.keys.clone()).unwrap());
+        (recorder, fired)
+    }
+
+    // feed hands the key events to the recorder the way the input backend
+    // does, and returns the verdict for each of them
+    fn feed(recorder: &mut KeyStrokeRecorder, events: Vec<KeyEvent>) -> Vec<Verdict> {
+        events.iter().map(|event| recorder.handle(event)).collect()
+    }
+
+    // replayed returns the names of the keys a verdict replays
+    fn replayed(verdict: &Verdict) -> Vec<&str> {
+        match verdict {
+            Verdict::Replay(events) => events.iter().map(|event| event.key.as_str()).collect(),
+            other => panic!("expected replay, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lookup_reports_prefix_for_partial_sequence() {
+        let trie = trie(CONFIG);
+
+        assert_eq!(trie.lookup(&["o"]), Match::Prefix);
+        assert_eq!(trie.lookup(&["o", "v"]), Match::Prefix);
+        assert_eq!(trie.lookup(&["r"]), Match::Prefix);
+    }
+
+    #[test]
+    fn lookup_reports_exact_for_complete_sequence() {
+        let trie = trie(CONFIG);
+
+        match trie.lookup(&["o", "v", "s"]) {
+            Match::Exact(mapping) => assert_eq!(mapping.action.command(), "Visual Studio Code"),
+            other => panic!("expected exact match, got {:?}", other),
+        }
+        match trie.lookup(&["r", "t"]) {
+            Match::Exact(mapping) => assert_eq!(mapping.action.command(), "true"),
+            other => panic!("expected exact match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lookup_reports_none_for_unknown_sequence() {
+        let trie = trie(CONFIG);
+
+        assert_eq!(trie.lookup(&["x"]), Match::None);
+        assert_eq!(trie.lookup(&["o", "x"]), Match::None);
+        assert_eq!(trie.lookup(&["o", "f", "f"]), Match::None);
+    }
+
+    #[test]
+    fn lookup_fires_shorter_sequence_first() {
+        let trie = trie(
+            r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "o"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "ovs"
+  kind = "Application"
+  command = "Visual Studio Code"
+"#,
+        );
+
+        match trie.lookup(&["o"]) {
+            Match::Exact(mapping) => assert_eq!(mapping.action.command(), "Finder"),
+            other => panic!("expected exact match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_sequence_consumes_keys_while_prefix_matches() {
+        let mut recorder = recorder(CONFIG);
+        let [down, up] = leader();
+
+        let verdicts = feed(&mut recorder, vec![down, up, stroke("o"), stroke("v")]);
+
+        assert_eq!(
+            verdicts,
+            vec![
+                Verdict::Pass,
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Consume
+            ]
+        );
+        assert_eq!(recorder.strokes.len(), 4);
+    }
+
+    #[test]
+    fn check_sequence_resets_after_exact_match() {
+        let (mut recorder, fired) = firing(CONFIG);
+        let [down, up] = leader();
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![down, up, stroke("r"), stroke("t"), stroke("x")],
+        );
+
+        assert_eq!(
+            verdicts,
+            vec![
+                Verdict::Pass,
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Consume,
+                Verdict::Pass
+            ]
+        );
+        assert_eq!(fired.try_iter().collect::<Vec<_>>(), ["rt"]);
+    }
+
+    #[test]
+    fn check_sequence_replays_swallowed_keys_on_mismatch() {
+        let mut recorder = recorder(CONFIG);
+        let [down, up] = leader();
+
+        let verdicts = feed(&mut recorder, vec![down, up, stroke("o"), stroke("x")]);
+
+        assert_eq!(replayed(&verdicts[3]), vec!["o", "x"]);
+        assert!(recorder.strokes.is_empty());
+    }
+
+    #[test]
+    fn check_sequence_replays_first_key_when_nothing_matches() {
+        let mut recorder = recorder(CONFIG);
+        let [down, up] = leader();
+
+        let verdicts = feed(&mut recorder, vec![down, up, stroke("x"), stroke("o")]);
+
+        assert_eq!(replayed(&verdicts[2]), vec!["x"]);
+        // The sequence is over, typing goes through again
+        assert_eq!(verdicts[3], Verdict::Pass);
+    }
+
+    #[test]
+    fn check_sequence_drops_swallowed_keys_on_mismatch() {
+        let mut recorder = recorder(&format!("on_abort = \"drop\"\n{}", CONFIG));
+        let [down, up] = leader();
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![down, up, stroke("o"), stroke("x"), stroke("v")],
+        );
+
+        assert_eq!(
+            verdicts,
+            vec![
+                Verdict::Pass,
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Consume,
+                Verdict::Pass
+            ]
+        );
+        assert_eq!(recorder.strokes.len(), 1);
+    }
+
+    #[test]
+    fn handle_ignores_key_up_of_other_keys() {
+        let mut recorder = recorder(CONFIG);
+        let [down, up] = leader();
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![down, up, stroke("o"), key("o", KeyState::Up), stroke("v")],
+        );
+
+        assert_eq!(verdicts[3], Verdict::Pass);
+        assert_eq!(verdicts[4], Verdict::Consume);
+        assert_eq!(recorder.strokes.len(), 4);
+    }
+
+    #[test]
+    fn handle_needs_leader_key_down_and_up() {
+        let mut recorder = recorder(CONFIG);
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![
+                key("option", KeyState::Down),
+                key("option", KeyState::Down),
+                stroke("o"),
+            ],
+        );
+
+        assert_eq!(verdicts, vec![Verdict::Pass, Verdict::Pass, Verdict::Pass]);
+    }
+
+    #[test]
+    fn handle_swallows_leader_key_that_is_not_a_modifier() {
+        let (mut recorder, fired) = firing(&CONFIG.replace(r#""option""#, r#""f18""#));
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![
+                key("f18", KeyState::Down),
+                key("f18", KeyState::Up),
+                stroke("r"),
+                stroke("t"),
+            ],
+        );
+
+        assert!(verdicts.iter().all(|verdict| *verdict == Verdict::Consume));
+        assert_eq!(fired.try_iter().collect::<Vec<_>>(), ["rt"]);
+    }
+
+    #[test]
+    fn handle_tells_right_option_leader_from_left_option() {
+        let mut recorder = recorder(&CONFIG.replace(r#""option""#, r#""rightoption""#));
+
+        let typed = feed(
+            &mut recorder,
+            vec![
+                key("option", KeyState::Down),
+                key("option", KeyState::Up),
+                stroke("r"),
+            ],
+        );
+        assert!(typed.iter().all(|verdict| *verdict == Verdict::Pass));
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![
+                key("rightoption", KeyState::Down),
+                key("rightoption", KeyState::Up),
+                stroke("r"),
+            ],
+        );
+        assert_eq!(
+            verdicts,
+            vec![Verdict::Pass, Verdict::Pass, Verdict::Consume]
+        );
+    }
+
+    #[test]
+    fn handle_starts_sequence_when_leader_follows_typing() {
+        let mut recorder = recorder(CONFIG);
+
+        let mut events = vec![stroke("r"), key("r", KeyState::Up)];
+        events.extend(leader());
+        events.extend([stroke("r"), stroke("t")]);
+        let verdicts = feed(&mut recorder, events);
+
+        assert_eq!(verdicts[4], Verdict::Consume);
+        assert_eq!(verdicts[5], Verdict::Consume);
+        assert!(recorder.strokes.is_empty());
+    }
+
+    #[test]
+    fn is_pending_while_sequence_is_under_way() {
+        let mut recorder = recorder(CONFIG);
+        let [down, up] = leader();
+
+        recorder.handle(&down);
+        assert!(!recorder.is_pending());
+        recorder.handle(&up);
+        assert!(recorder.is_pending());
+        recorder.handle(&stroke("r"));
+        assert!(recorder.is_pending());
+        recorder.handle(&stroke("t"));
+        assert!(!recorder.is_pending());
+    }
+
+    #[test]
+    fn record_keeps_strokes_typed_within_interval() {
+        let clock = ManualClock::new();
+        let mut recorder = recorder(CONFIG).with_clock(clock.clone());
+
+        recorder.handle(&at(&clock, "option", KeyState::Down));
+        recorder.handle(&at(&clock, "option", KeyState::Up));
+        clock.advance(Duration::from_millis(config::DEFAULT_TIMEOUT_MS));
+        assert_eq!(
+            recorder.handle(&at(&clock, "r", KeyState::Down)),
+            Verdict::Consume
+        );
+        clock.advance(Duration::from_millis(config::DEFAULT_TIMEOUT_MS));
+        assert_eq!(
+            recorder.handle(&at(&clock, "t", KeyState::Down)),
+            Verdict::Consume
+        );
+        assert!(recorder.strokes.is_empty());
+    }
+
+    #[test]
+    fn record_starts_over_after_interval() {
+        let clock = ManualClock::new();
+        let mut recorder = recorder(CONFIG).with_clock(clock.clone());
+
+        recorder.handle(&at(&clock, "option", KeyState::Down));
+        recorder.handle(&at(&clock, "option", KeyState::Up));
+        clock.advance(Duration::from_millis(config::DEFAULT_TIMEOUT_MS + 1));
+        assert_eq!(
+            recorder.handle(&at(&clock, "r", KeyState::Down)),
+            Verdict::Pass
+        );
+        assert_eq!(recorder.strokes.len(), 1);
+    }
+
+    #[test]
+    fn is_pending_until_interval_elapses() {
+        let clock = ManualClock::new();
+        let mut recorder = recorder(CONFIG).with_clock(clock.clone());
+
+        recorder.handle(&at(&clock, "option", KeyState::Down));
+        recorder.handle(&at(&clock, "option", KeyState::Up));
+        clock.advance(Duration::from_millis(config::DEFAULT_TIMEOUT_MS));
+        assert!(recorder.is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert!(!recorder.is_pending());
+    }
+
+    const TIMEOUTS: &str = r#"
+leader_key = "option"
+timeout_ms = 2000
+
+[[groups]]
+name = "Open Applications"
+timeout_ms = 500
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+
+  [[groups.mappings]]
+  keys = "ovs"
+  kind = "Application"
+  command = "Visual Studio Code"
+  timeout_ms = 3000
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "rt"
+  kind = "Command"
+  command = "true"
+
+  [[groups.mappings]]
+  keys = "rb"
+  kind = "Command"
+  command = "true"
+  timeout_ms = 300
+"#;
+
+    #[test]
+    fn timeout_is_tightest_of_mappings_under_prefix() {
+        let trie = trie(TIMEOUTS);
+        let ms = Duration::from_millis;
+
+        assert_eq!(trie.timeout::<&str>(&[]), Some(ms(300)));
+        assert_eq!(trie.timeout(&["o"]), Some(ms(500)));
+        assert_eq!(trie.timeout(&["o", "v"]), Some(ms(3000)));
+        assert_eq!(trie.timeout(&["r"]), Some(ms(300)));
+        assert_eq!(trie.timeout(&["x"]), None);
+    }
+
+    #[test]
+    fn timeout_defaults_to_one_second() {
+        let trie = trie(CONFIG);
+        assert_eq!(trie.timeout(&["o"]), Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn record_goes_by_timeout_of_prefix() {
+        let clock = ManualClock::new();
+        let (sender, fired) = std::sync::mpsc::channel();
+        let mut recorder = recorder(TIMEOUTS)
+            .with_clock(clock.clone())
+            .on_fire(move |mapping| sender.send(mapping.keys.clone()).unwrap());
+        let mut type_after = |ms: u64, name: &str, state: KeyState| {
+            clock.advance(Duration::from_millis(ms));
+            recorder.handle(&at(&clock, name, state))
+        };
+
+        type_after(0, "option", KeyState::Down);
+        type_after(0, "option", KeyState::Up);
+        // Right after the leader key, "rb" is the tightest with 300ms
+        assert_eq!(type_after(300, "o", KeyState::Down), Verdict::Consume);
+        // After "o", "of" is the tightest with 500ms
+        assert_eq!(type_after(500, "v", KeyState::Down), Verdict::Consume);
+        // After "ov", only "ovs" is left with 3000ms
+        assert_eq!(type_after(3000, "s", KeyState::Down), Verdict::Consume);
+        assert_eq!(fired.try_recv(), Ok("ovs".to_string()));
+
+        type_after(0, "option", KeyState::Down);
+        type_after(0, "option", KeyState::Up);
+        assert_eq!(type_after(301, "r", KeyState::Down), Verdict::Pass);
+    }
+
+    #[test]
+    fn hints_list_next_keys_with_groups_and_mappings() {
+        let trie = trie(CONFIG);
+
+        assert_eq!(
+            trie.hints::<&str>(&[]),
+            [
+                Hint {
+                    key: "o".to_string(),
+                    label: "Open Applications".to_string(),
+                    is_prefix: true,
+                },
+                Hint {
+                    key: "r".to_string(),
+                    label: "Run commands".to_string(),
+                    is_prefix: true,
+                },
+            ]
+        );
+        assert_eq!(
+            trie.hints(&["o"]),
+            [
+                Hint {
+                    key: "f".to_string(),
+                    label: "Finder".to_string(),
+                    is_prefix: false,
+                },
+                Hint {
+                    key: "v".to_string(),
+                    label: "Open Applications".to_string(),
+                    is_prefix: true,
+                },
+            ]
+        );
+        assert!(trie.hints(&["x"]).is_empty());
+    }
+
+    #[test]
+    fn hints_prefer_mapping_description() {
+        let trie = trie(
+            r#"
+leader_key = "option"
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "rt"
+  kind = "Command"
+  command = "true"
+  description = "Do nothing"
+"#,
+        );
+
+        assert_eq!(trie.hints(&["r"])[0].label, "Do nothing");
+    }
+
+    #[test]
+    fn hints_label_prefixes_from_config() {
+        let trie = trie(&format!("{}\n[prefixes]\nov = \"Editors\"\n", CONFIG));
+
+        assert_eq!(trie.hints(&["o"])[1].label, "Editors");
+        assert_eq!(trie.hints::<&str>(&[])[0].label, "Open Applications");
+    }
+
+    // RecordingDisplay keeps the keys of every popup shown, None when it's
+    // hidden
+    struct RecordingDisplay(std::sync::mpsc::Sender<Option<Vec<String>>>);
+
+    impl HintDisplay for RecordingDisplay {
+        fn show(&mut self, _keys: &[&str], hints: &[Hint], _timeout: Duration) {
+            let keys = hints.iter().map(|hint| hint.key.clone()).collect();
+            let _ = self.0.send(Some(keys));
+        }
+
+        fn hide(&mut self) {
+            let _ = self.0.send(None);
+        }
+    }
+
+    #[test]
+    fn handle_shows_hints_until_sequence_is_over() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut recorder = recorder(CONFIG).with_display(Box::new(RecordingDisplay(sender)));
+
+        feed(
+            &mut recorder,
+            vec![
+                key("option", KeyState::Down),
+                key("option", KeyState::Up),
+                stroke("o"),
+                key("o", KeyState::Up),
+                stroke("f"),
+                stroke("x"),
+            ],
+        );
+
+        let shown: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            shown,
+            [
+                Some(vec!["o".to_string(), "r".to_string()]),
+                Some(vec!["f".to_string(), "v".to_string()]),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_swaps_reloaded_config_between_sequences() {
+        let (sender, reloads) = std::sync::mpsc::channel();
+        let (fire, fired) = std::sync::mpsc::channel();
+        let mut recorder = recorder(CONFIG)
+            .on_fire(move |mapping| {
+                let _ = fire.send(mapping.action.command().to_string());
+            })
+            .with_reloads(reloads);
+
+        let mut events = leader().to_vec();
+        events.push(stroke("o"));
+        feed(&mut recorder, events);
+        sender
+            .send(toml::from_str(&CONFIG.replace("Finder", "Safari")).unwrap())
+            .unwrap();
+        feed(&mut recorder, vec![stroke("f")]);
+
+        let mut events = leader().to_vec();
+        events.extend([stroke("o"), stroke("f")]);
+        feed(&mut recorder, events);
+
+        let fired: Vec<String> = fired.try_iter().collect();
+        assert_eq!(fired, ["Finder", "Safari"]);
+    }
+
+    #[test]
+    fn trigger_fires_complete_mapping_only() {
+        let (fire, fired) = std::sync::mpsc::channel();
+        let mut recorder = recorder(&CONFIG.replace("\"of\"", "\"o\"")).on_fire(move |mapping| {
+            let _ = fire.send(mapping.keys.clone());
+        });
+
+        assert_eq!(recorder.resolve("o").unwrap().action.command(), "Finder");
+        assert_eq!(
+            recorder.resolve("ovs"),
+            Err(r#""ovs" fires "o" first"#.to_string())
+        );
+        assert_eq!(
+            recorder.resolve("r"),
+            Err(r#""r" is only the beginning of mappings"#.to_string())
+        );
+        assert_eq!(
+            recorder.resolve("x"),
+            Err(r#"No mapping for "x""#.to_string())
+        );
+        assert!(fired.try_recv().is_err());
+
+        assert_eq!(recorder.trigger("rt").unwrap().keys, "rt");
+        assert!(recorder.trigger("r").is_err());
+        assert_eq!(fired.try_iter().collect::<Vec<_>>(), ["rt"]);
+    }
+
+    #[test]
+    fn keys_typed_with_modifiers_match_notation() {
+        let mut recorder = recorder(
+            &CONFIG
+                .replace("\"of\"", "\"oF\"")
+                .replace("\"rt\"", "\"r<C-t>\""),
+        );
+        let held = |name: &str, modifiers: Modifiers| KeyEvent {
+            modifiers,
+            ..stroke(name)
+        };
+        let shift = Modifiers {
+            shift: true,
+            ..Modifiers::default()
+        };
+        let control = Modifiers {
+            control: true,
+            ..Modifiers::default()
+        };
+
+        let mut events = leader().to_vec();
+        events.extend([stroke("o"), stroke("shift"), held("f", shift)]);
+        let verdicts = feed(&mut recorder, events);
+        assert_eq!(verdicts[3], Verdict::Pass);
+        assert_eq!(verdicts[4], Verdict::Consume);
+        assert!(recorder.strokes.is_empty());
+
+        let mut events = leader().to_vec();
+        events.extend([stroke("r"), stroke("t")]);
+        let verdicts = feed(&mut recorder, events);
+        assert_eq!(replayed(&verdicts[3]), ["r", "t"]);
+
+        let mut events = leader().to_vec();
+        events.extend([stroke("control"), stroke("r"), held("t", control)]);
+        let verdicts = feed(&mut recorder, events);
+        assert_eq!(
+            verdicts[2..],
+            [Verdict::Pass, Verdict::Consume, Verdict::Consume]
+        );
+        assert_eq!(recorder.resolve("r<c-t>").unwrap().keys, "r<C-t>");
+    }
+
+    // activated is the recorder of CONFIG with the leader key activated
+    // the way given, its mappings don't run anything
+    fn activated(leader_key: &str, activation: &str) -> KeyStrokeRecorder {
+        let content = CONFIG.replace(
+            r#"leader_key = "option""#,
+            &format!(
+                "leader_key = {:?}\nleader_activation = {:?}",
+                leader_key, activation
+            ),
+        );
+        recorder(&content)
+    }
+
+    // timed returns the key events stamped with their milliseconds after
+    // the start, like the scripts of whichkey simulate
+    fn timed(start: Instant, events: &[(u64, &str, KeyState)]) -> Vec<KeyEvent> {
+        events
+            .iter()
+            .map(|(ms, name, state)| KeyEvent {
+                timestamp: start + Duration::from_millis(*ms),
+                ..key(name, *state)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tap_needs_leader_released_quickly_on_its_own() {
+        let mut recorder = activated("option", "tap");
+        let start = Instant::now();
+
+        // Held too long, like for option and click
+        let verdicts = feed(
+            &mut recorder,
+            timed(
+                start,
+                &[
+                    (0, "option", KeyState::Down),
+                    (600, "option", KeyState::Up),
+                    (700, "r", KeyState::Down),
+                ],
+            ),
+        );
+        assert_eq!(verdicts[2], Verdict::Pass);
+
+        // Another key in between, like for option and an arrow
+        let verdicts = feed(
+            &mut recorder,
+            timed(
+                start,
+                &[
+                    (1000, "option", KeyState::Down),
+                    (1050, "left", KeyState::Down),
+                    (1100, "option", KeyState::Up),
+                    (1200, "r", KeyState::Down),
+                ],
+            ),
+        );
+        assert!(verdicts.iter().all(|verdict| *verdict == Verdict::Pass));
+
+        let verdicts = feed(
+            &mut recorder,
+            timed(
+                start,
+                &[
+                    (2000, "option", KeyState::Down),
+                    (2100, "option", KeyState::Up),
+                    (2200, "r", KeyState::Down),
+                    (2300, "t", KeyState::Down),
+                ],
+            ),
+        );
+        assert_eq!(verdicts[2..], [Verdict::Consume, Verdict::Consume]);
+        assert!(recorder.strokes.is_empty());
+    }
+
+    #[test]
+    fn tap_is_broken_by_a_click() {
+        let mut recorder = activated("option", "tap");
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![
+                key("option", KeyState::Down),
+                key(MOUSE_CLICK, KeyState::Down),
+                key("option", KeyState::Up),
+                stroke("r"),
+                stroke("t"),
+            ],
+        );
+
+        assert!(verdicts.iter().all(|verdict| *verdict == Verdict::Pass));
+        assert!(!recorder.is_pending());
+
+        // Within a sequence, the keys typed so far are given back
+        let mut events = leader().to_vec();
+        events.extend([stroke("r"), key(MOUSE_CLICK, KeyState::Down)]);
+        let verdicts = feed(&mut recorder, events);
+        assert_eq!(replayed(&verdicts[3]), ["r"]);
+    }
+
+    #[test]
+    fn double_tap_needs_two_taps_in_a_row() {
+        let mut recorder = activated("option", "double_tap");
+        let start = Instant::now();
+
+        let verdicts = feed(
+            &mut recorder,
+            timed(
+                start,
+                &[
+                    (0, "option", KeyState::Down),
+                    (50, "option", KeyState::Up),
+                    (200, "r", KeyState::Down),
+                ],
+            ),
+        );
+        assert_eq!(verdicts[2], Verdict::Pass);
+
+        // The second tap comes too late
+        let verdicts = feed(
+            &mut recorder,
+            timed(
+                start,
+                &[
+                    (1000, "option", KeyState::Down),
+                    (1050, "option", KeyState::Up),
+                    (1700, "option", KeyState::Down),
+                    (1750, "option", KeyState::Up),
+                    (1800, "r", KeyState::Down),
+                ],
+            ),
+        );
+        assert_eq!(verdicts[4], Verdict::Pass);
+
+        let verdicts = feed(
+            &mut recorder,
+            timed(
+                start,
+                &[
+                    (3000, "option", KeyState::Down),
+                    (3050, "option", KeyState::Up),
+                    (3150, "option", KeyState::Down),
+                    (3200, "option", KeyState::Up),
+                    (3300, "r", KeyState::Down),
+                    (3400, "t", KeyState::Down),
+                ],
+            ),
+        );
+        assert_eq!(verdicts[4..], [Verdict::Consume, Verdict::Consume]);
+        assert!(recorder.strokes.is_empty());
+    }
+
+    #[test]
+    fn hold_takes_keys_typed_while_leader_is_held() {
+        let mut recorder = activated("rightoption", "hold");
+        let option = Modifiers {
+            option: true,
+            ..Modifiers::default()
+        };
+        let held = |name: &str, state: KeyState| KeyEvent {
+            modifiers: option,
+            ..key(name, state)
+        };
+
+        // Its repeats aren't keys, and it goes on after a mapping fires
+        let verdicts = feed(
+            &mut recorder,
+            vec![
+                held("rightoption", KeyState::Down),
+                held("rightoption", KeyState::Down),
+                held("r", KeyState::Down),
+                held("r", KeyState::Up),
+                held("t", KeyState::Down),
+                held("r", KeyState::Down),
+            ],
+        );
+        assert_eq!(
+            verdicts,
+            vec![
+                Verdict::Pass,
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Consume
+            ]
+        );
+
+        // Released before the sequence is over, the keys are given back
+        // and the leader key goes up
+        let verdict = recorder.handle(&key("rightoption", KeyState::Up));
+        assert_eq!(replayed(&verdict), ["r", "rightoption"]);
+        assert!(!recorder.is_pending());
+
+        assert_eq!(recorder.handle(&stroke("r")), Verdict::Pass);
+    }
+
+    #[test]
+    fn hold_waits_for_first_key_as_long_as_leader_is_held() {
+        let clock = ManualClock::new();
+        let mut recorder = activated("rightoption", "hold").with_clock(clock.clone());
+        let held = |name: &str, state: KeyState| KeyEvent {
+            modifiers: Modifiers {
+                option: true,
+                ..Modifiers::default()
+            },
+            ..at(&clock, name, state)
+        };
+
+        assert_eq!(
+            recorder.handle(&held("rightoption", KeyState::Down)),
+            Verdict::Pass
+        );
+        clock.advance(Duration::from_millis(2 * config::DEFAULT_TIMEOUT_MS));
+        assert!(recorder.is_pending());
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![held("r", KeyState::Down), held("t", KeyState::Down)],
+        );
+        assert_eq!(verdicts, vec![Verdict::Consume, Verdict::Consume]);
+
+        // It waits again once a mapping fired, but past the first key the
+        // sequence times out as usual
+        clock.advance(Duration::from_millis(2 * config::DEFAULT_TIMEOUT_MS));
+        assert!(recorder.is_pending());
+        assert_eq!(
+            recorder.handle(&held("r", KeyState::Down)),
+            Verdict::Consume
+        );
+        clock.advance(Duration::from_millis(2 * config::DEFAULT_TIMEOUT_MS));
+        assert!(!recorder.is_pending());
+    }
+
+    #[test]
+    fn chord_takes_key_typed_with_its_modifiers() {
+        let mut recorder = activated("<C-space>", "chord");
+        let control = Modifiers {
+            control: true,
+            ..Modifiers::default()
+        };
+        let held = |name: &str, state: KeyState| KeyEvent {
+            modifiers: control,
+            ..key(name, state)
+        };
+
+        assert_eq!(recorder.handle(&stroke("space")), Verdict::Pass);
+
+        let verdicts = feed(
+            &mut recorder,
+            vec![
+                stroke("control"),
+                held("space", KeyState::Down),
+                held("space", KeyState::Up),
+                key("control", KeyState::Up),
+                stroke("r"),
+                stroke("t"),
+            ],
+        );
+        assert_eq!(
+            verdicts,
+            vec![
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Pass,
+                Verdict::Pass,
+                Verdict::Consume,
+                Verdict::Consume
+            ]
+        );
+        assert!(recorder.strokes.is_empty());
     }
 }
@@ -0,0 +1,361 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use crate::clock::{Clock, ManualClock};
+use crate::config;
+use crate::input::{InputBackend, KeyEvent, KeyEventHandler, KeyState, Modifiers, Verdict};
+use crate::sequence::KeyStrokeRecorder;
+
+// ScriptedEvent is a key event of a script, at its offset from the start
+// of the script
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptedEvent {
+    pub offset: Duration,
+    pub key: String,
+    pub modifiers: Modifiers,
+    pub state: KeyState,
+}
+
+// Step is what happened to a scripted event: the verdict of the handler and
+// the mappings it fired
+#[derive(Debug)]
+pub struct Step {
+    pub event: ScriptedEvent,
+    pub verdict: Verdict,
+    pub fired: Vec<config::Mapping>,
+}
+
+// SimulatedBackend plays a script of key events instead of capturing the
+// keyboard, and keeps what happened to each of them. Nothing is sent to the
+// system, and no real time passes: the events are timestamped with their
+// offsets.
+pub struct SimulatedBackend {
+    script: Vec<ScriptedEvent>,
+    clock: ManualClock,
+    fired: Receiver<config::Mapping>,
+    pub steps: Vec<Step>,
+}
+
+impl SimulatedBackend {
+    // The clock is moved to each event before it's handled, it's the one
+    // the handler should go by. fired receives the mappings the handler
+    // fires, see KeyStrokeRecorder::on_fire.
+    pub fn new(
+        script: Vec<ScriptedEvent>,
+        clock: ManualClock,
+        fired: Receiver<config::Mapping>,
+    ) -> Self {
+        SimulatedBackend {
+            script,
+            clock,
+            fired,
+            steps: vec![],
+        }
+    }
+}
+
+impl InputBackend for SimulatedBackend {
+    fn run(&mut self, mut handler: Box<dyn KeyEventHandler>) -> Result<(), Error> {
+        let mut offset = Duration::ZERO;
+        for event in self.script.iter() {
+            self.clock.advance(event.offset.saturating_sub(offset));
+            offset = event.offset;
+            let verdict = handler.handle(&KeyEvent {
+                key: event.key.clone(),
+                modifiers: event.modifiers,
+                state: event.state,
+                timestamp: self.clock.now(),
+            });
+
+            self.steps.push(Step {
+                event: event.clone(),
+                verdict,
+                fired: self.fired.try_iter().collect(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// simulate runs the script through the sequence recorder of the config, and
+// returns what happened to each key event
+pub fn simulate(config: config::Config, script: &str) -> Result<Vec<Step>, Error> {
+    let script = parse_script(script)?;
+    let clock = ManualClock::new();
+    let (sender, fired) = mpsc::channel();
+    let recorder = KeyStrokeRecorder::with_config(config)
+        .with_clock(clock.clone())
+        .on_fire(move |mapping| {
+            let _ = sender.send(mapping.clone());
+        });
+
+    let mut backend = SimulatedBackend::new(script, clock, fired);
+    backend.run(Box::new(recorder))?;
+    Ok(backend.steps)
+}
+
+// parse_script reads a script of key events, one per line, each with its
+// offset from the start of the script in milliseconds:
+//
+//     # leader key, then "of"
+//     0   option-down
+//     50  option-up
+//     200 o
+//     300 shift+f
+//
+// A key without -down or -up is pressed and released at once. Modifiers
+// held with the key go before it, joined with "+". Blank lines and
+// everything after "#" are ignored.
+pub fn parse_script(script: &str) -> Result<Vec<ScriptedEvent>, Error> {
+    let mut events = vec![];
+    for (number, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |message: String| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, message),
+            )
+        };
+
+        let mut fields = line.split_whitespace();
+        let (Some(offset), Some(stroke), None) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid(format!(
+                "expected an offset and a key, got {:?}",
+                line
+            )));
+        };
+
+        let offset = offset
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| invalid(format!("invalid offset {:?}", offset)))?;
+        if events
+            .last()
+            .is_some_and(|last: &ScriptedEvent| last.offset > offset)
+        {
+            return Err(invalid("offsets must not go backwards".to_string()));
+        }
+
+        let (modifiers, key, state) = parse_stroke(stroke).map_err(invalid)?;
+        let event = ScriptedEvent {
+            offset,
+            key: key.to_string(),
+            modifiers,
+            state: state.unwrap_or(KeyState::Down),
+        };
+
+        if state.is_none() {
+            events.push(event.clone());
+            events.push(ScriptedEvent {
+                state: KeyState::Up,
+                ..event
+            });
+        } else {
+            events.push(event);
+        }
+    }
+
+    Ok(events)
+}
+
+// parse_stroke splits "shift+f-down" into its modifiers, key and state
+fn parse_stroke(stroke: &str) -> Result<(Modifiers, &str, Option<KeyState>), String> {
+    let (key, state) = match stroke.rsplit_once('-') {
+        Some((key, "down")) if !key.is_empty() => (key, Some(KeyState::Down)),
+        Some((key, "up")) if !key.is_empty() => (key, Some(KeyState::Up)),
+        _ => (stroke, None),
+    };
+
+    let mut modifiers = Modifiers::default();
+    let mut parts: Vec<&str> = key.split('+').collect();
+    // "+" on its own, or at the end as in "shift++", is the key itself
+    let key = match parts.pop() {
+        Some("") if key.ends_with('+') => {
+            parts.pop();
+            "+"
+        }
+        Some(key) => key,
+        None => key,
+    };
+
+    for modifier in parts {
+        match modifier {
+            "shift" => modifiers.shift = true,
+            "control" => modifiers.control = true,
+            "option" => modifiers.option = true,
+            "command" => modifiers.command = true,
+            _ => return Err(format!("unknown modifier {:?} in {:?}", modifier, stroke)),
+        }
+    }
+
+    if key.is_empty() {
+        return Err(format!("missing key in {:?}", stroke));
+    }
+
+    Ok((modifiers, key, state))
+}
+
+impl fmt::Display for ScriptedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = vec![];
+        for (held, name) in [
+            (self.modifiers.shift, "shift"),
+            (self.modifiers.control, "control"),
+            (self.modifiers.option, "option"),
+            (self.modifiers.command, "command"),
+        ] {
+            if held {
+                names.push(name);
+            }
+        }
+        names.push(&self.key);
+
+        let state = match self.state {
+            KeyState::Down => "down",
+            KeyState::Up => "up",
+        };
+        write!(f, "{}-{}", names.join("+"), state)
+    }
+}
+
+// Steps are printed one per line, e.g.:
+//
+//     200ms  o-down       consume
+//     300ms  f-down       consume  fires "of": Application Finder
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = match &self.verdict {
+            Verdict::Pass => "pass".to_string(),
+            Verdict::Consume => "consume".to_string(),
+            Verdict::Replay(events) => {
+                let keys: Vec<&str> = events.iter().map(|event| event.key.as_str()).collect();
+                format!("replay {}", keys.join(" "))
+            }
+        };
+
+        let line = format!(
+            "{:>6}ms  {:<12} {}",
+            self.event.offset.as_millis(),
+            self.event.to_string(),
+            verdict
+        );
+        write!(f, "{}", line.trim_end())?;
+        for mapping in self.fired.iter() {
+            write!(f, "  fires {:?}: {}", mapping.keys, mapping.action)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    const CONFIG: &str = r#"
+leader_key = "option"
+
+[[groups]]
+name = "Open Applications"
+
+  [[groups.mappings]]
+  keys = "of"
+  kind = "Application"
+  command = "Finder"
+"#;
+
+    fn output(steps: &[Step]) -> String {
+        steps.iter().map(|step| format!("{}\n", step)).collect()
+    }
+
+    #[test]
+    fn parse_script_presses_keys_without_state() {
+        let events = parse_script("0 option-down\n10 option-up\n\n# keys\n20 shift+o").unwrap();
+        let strokes: Vec<String> = events.iter().map(|event| event.to_string()).collect();
+
+        assert_eq!(
+            strokes,
+            ["option-down", "option-up", "shift+o-down", "shift+o-up"]
+        );
+        assert_eq!(events[3].offset, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn parse_script_reads_minus_and_plus_keys() {
+        let events = parse_script("0 -\n10 --up\n20 shift++-down").unwrap();
+        let keys: Vec<&str> = events.iter().map(|event| event.key.as_str()).collect();
+
+        assert_eq!(keys, ["-", "-", "-", "+"]);
+        assert!(events[3].modifiers.shift);
+    }
+
+    #[test]
+    fn parse_script_reports_line_of_invalid_events() {
+        let err = parse_script("0 option-down\n\nsoon o").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: invalid offset \"soon\"");
+
+        let err = parse_script("10 o\n5 f").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: offsets must not go backwards");
+
+        let err = parse_script("0 hyper+o").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: unknown modifier \"hyper\" in \"hyper+o\""
+        );
+    }
+
+    #[test]
+    fn simulate_reports_fired_mappings() {
+        let config = toml::from_str(CONFIG).unwrap();
+        let steps = simulate(config, "0 option-down\n10 option-up\n20 o\n30 f").unwrap();
+
+        let fired: Vec<&str> = steps
+            .iter()
+            .flat_map(|step| step.fired.iter())
+            .map(|mapping| mapping.action.command())
+            .collect();
+        assert_eq!(fired, ["Finder"]);
+        assert_eq!(steps[2].verdict, Verdict::Consume);
+        assert!(steps[2].fired.is_empty());
+        assert_eq!(steps[4].verdict, Verdict::Consume);
+        assert_eq!(steps[4].fired.len(), 1);
+    }
+
+    #[test]
+    fn simulate_times_out_slow_sequences() {
+        let config = toml::from_str(CONFIG).unwrap();
+        let steps = simulate(config, "0 option-down\n10 option-up\n20 o\n1500 f").unwrap();
+
+        assert!(steps.iter().all(|step| step.fired.is_empty()));
+        assert_eq!(steps[4].verdict, Verdict::Pass);
+    }
+
+    // Every directory in tests/simulate is a case: its config.toml, the
+    // script in keys, and the expected output of `whichkey simulate`
+    #[test]
+    fn simulate_cases_match_expected_output() {
+        let cases = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/simulate");
+        let mut count = 0;
+        for case in fs::read_dir(&cases).unwrap() {
+            let case = case.unwrap().path();
+            let read = |name: &str| fs::read_to_string(case.join(name)).unwrap();
+
+            let config = toml::from_str(&read("config.toml")).unwrap();
+            let steps = simulate(config, &read("keys")).unwrap();
+            assert_eq!(output(&steps), read("expected"), "case {}", case.display());
+            count += 1;
+        }
+
+        assert!(count > 0, "no cases in {}", cases.display());
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::record::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{self, ConnectionExt as _, GrabMode, GrabStatus, Keycode, Window};
+use x11rb::protocol::xtest::{self, ConnectionExt as _};
+use x11rb::wrapper::ConnectionExt as _;
+use x11rb::CURRENT_TIME;
+
+use crate::config::KeyNames;
+use crate::input::{InputBackend, KeyEvent, KeyEventHandler, KeyState, Modifiers, Verdict};
+use crate::layout::{localize, Layout};
+
+// How often the grab is checked while no key is hit, so the keyboard is
+// released once the sequence times out
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Category of the RECORD replies carrying the recorded events
+const RECORD_FROM_SERVER: u8 = 0;
+
+// Size of a core X11 event
+const EVENT_SIZE: usize = 32;
+
+// X11Backend watches the key events of every client through the RECORD
+// extension, so normal typing never goes through whichkey. The keyboard is
+// grabbed only while a sequence is under way, and the keys the handler
+// lets through meanwhile are given back with the XTEST extension.
+pub struct X11Backend {
+    key_names: KeyNames,
+    stop: Arc<AtomicBool>,
+}
+
+// Session is the state of the backend while it runs
+struct Session {
+    root: Window,
+    keymap: Keymap,
+    grabbed: bool,
+    held: HashSet<Keycode>,
+    // Keys sent through XTEST, they are recorded like the others and must
+    // not go to the handler again
+    replayed: VecDeque<(Keycode, bool)>,
+}
+
+impl X11Backend {
+    pub fn new(key_names: KeyNames) -> Self {
+        X11Backend {
+            key_names,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl InputBackend for X11Backend {
+    fn run(&mut self, handler: Box<dyn KeyEventHandler>) -> Result<(), Error> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(Error::other)?;
+        for extension in [record::X11_EXTENSION_NAME, xtest::X11_EXTENSION_NAME] {
+            if conn
+                .extension_information(extension)
+                .map_err(Error::other)?
+                .is_none()
+            {
+                return Err(Error::other(format!(
+                    "The X server doesn't support the {} extension",
+                    extension
+                )));
+            }
+        }
+
+        let keymap = Keymap::load(&conn, self.key_names)?;
+        let mut handler = localize(handler, keymap.layout.clone());
+        let mut session = Session {
+            root: conn.setup().roots[screen_num].root,
+            keymap,
+            grabbed: false,
+            held: HashSet::new(),
+            replayed: VecDeque::new(),
+        };
+
+        let context = conn.generate_id().map_err(Error::other)?;
+        conn.record_create_context(
+            context,
+            0,
+            &[record::CS::ALL_CLIENTS.into()],
+            &[key_range()],
+        )
+        .map_err(Error::other)?
+        .check()
+        .map_err(Error::other)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let recording = thread::spawn(move || record_keys(context, sender));
+
+        log::info!("Monitoring input events via X11 RECORD.");
+        let mut result = Ok(());
+        while !self.stop.load(Ordering::SeqCst) {
+            match receiver.recv_timeout(POLL_TIMEOUT) {
+                Ok((keycode, pressed)) => {
+                    result = session.on_key(&conn, handler.as_mut(), keycode, pressed);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    result = Err(Error::other("X11 key recording stopped"));
+                }
+            }
+            if result.is_err() {
+                break;
+            }
+
+            // The key events of the grab are recorded too, they are of no use
+            while conn.poll_for_event().map_err(Error::other)?.is_some() {}
+            session.sync_grab(&conn, handler.is_pending())?;
+        }
+
+        session.sync_grab(&conn, false)?;
+        conn.record_disable_context(context).map_err(Error::other)?;
+        conn.record_free_context(context).map_err(Error::other)?;
+        conn.sync().map_err(Error::other)?;
+        drop(receiver);
+        match recording.join() {
+            Ok(recorded) => recorded?,
+            Err(_) => log::error!("X11 key recording panicked"),
+        }
+
+        result
+    }
+}
+
+impl Session {
+    fn on_key(
+        &mut self,
+        conn: &impl Connection,
+        handler: &mut dyn KeyEventHandler,
+        keycode: Keycode,
+        pressed: bool,
+    ) -> Result<(), Error> {
+        if self.replayed.front() == Some(&(keycode, pressed)) {
+            self.replayed.pop_front();
+            return Ok(());
+        }
+
+        if pressed {
+            self.held.insert(keycode);
+        } else {
+            self.held.remove(&keycode);
+        }
+
+        let Some(name) = self.keymap.names.get(&keycode) else {
+            return Ok(());
+        };
+
+        let verdict = handler.handle(&KeyEvent {
+            key: name.clone(),
+            modifiers: self.modifiers(),
+            state: if pressed {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            },
+            timestamp: Instant::now(),
+        });
+
+        let was_grabbed = self.grabbed;
+        self.sync_grab(conn, handler.is_pending())?;
+
+        match verdict {
+            // The grab kept the key from the application, give it back
+            Verdict::Pass if was_grabbed && !self.grabbed => {
+                self.replay(conn, vec![(keycode, pressed)])
+            }
+            Verdict::Pass | Verdict::Consume => Ok(()),
+            Verdict::Replay(events) => {
+                let keys = events
+                    .iter()
+                    .filter_map(|event| self.keymap.codes.get(&event.key))
+                    .flat_map(|keycode| [(*keycode, true), (*keycode, false)])
+                    .collect();
+                self.replay(conn, keys)
+            }
+        }
+    }
+
+    // sync_grab grabs the keyboard while a sequence is pending, and
+    // releases it otherwise
+    fn sync_grab(&mut self, conn: &impl Connection, pending: bool) -> Result<(), Error> {
+        if pending && !self.grabbed {
+            let reply = conn
+                .grab_keyboard(
+                    false,
+                    self.root,
+                    CURRENT_TIME,
+                    GrabMode::ASYNC,
+                    GrabMode::ASYNC,
+                )
+                .map_err(Error::other)?
+                .reply()
+                .map_err(Error::other)?;
+            if reply.status == GrabStatus::SUCCESS {
+                self.grabbed = true;
+            } else {
+                log::warn!("Failed to grab the keyboard: {:?}", reply.status);
+            }
+        } else if !pending && self.grabbed {
+            conn.ungrab_keyboard(CURRENT_TIME).map_err(Error::other)?;
+            conn.flush().map_err(Error::other)?;
+            self.grabbed = false;
+        }
+
+        Ok(())
+    }
+
+    fn replay(&mut self, conn: &impl Connection, keys: Vec<(Keycode, bool)>) -> Result<(), Error> {
+        for (keycode, pressed) in keys {
+            let type_ = if pressed {
+                xproto::KEY_PRESS_EVENT
+            } else {
+                xproto::KEY_RELEASE_EVENT
+            };
+            conn.xtest_fake_input(type_, keycode, CURRENT_TIME, self.root, 0, 0, 0)
+                .map_err(Error::other)?;
+            self.replayed.push_back((keycode, pressed));
+        }
+
+        conn.flush().map_err(Error::other)
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let held = |names: [&str; 2]| {
+            self.held.iter().any(|keycode| {
+                self.keymap
+                    .names
+                    .get(keycode)
+                    .is_some_and(|name| names.contains(&name.as_str()))
+            })
+        };
+        Modifiers {
+            shift: held(["shift", "rightshift"]),
+            control: held(["control", "rightcontrol"]),
+            option: held(["option", "rightoption"]),
+            command: held(["command", "rightcommand"]),
+        }
+    }
+}
+
+// record_keys sends the key presses and releases of every client, it needs
+// a connection of its own as the recording blocks it
+fn record_keys(context: record::Context, sender: Sender<(Keycode, bool)>) -> Result<(), Error> {
+    let (conn, _) = x11rb::connect(None).map_err(Error::other)?;
+    for reply in conn.record_enable_context(context).map_err(Error::other)? {
+        let reply = reply.map_err(Error::other)?;
+        if reply.category != RECORD_FROM_SERVER {
+            continue;
+        }
+
+        for key in parse_recorded_keys(&reply.data) {
+            if sender.send(key).is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// key_range asks RECORD for the key presses and releases of the devices
+fn key_range() -> record::Range {
+    let empty = record::Range8 { first: 0, last: 0 };
+    let empty_ext = record::ExtRange {
+        major: empty,
+        minor: record::Range16 { first: 0, last: 0 },
+    };
+    record::Range {
+        core_requests: empty,
+        core_replies: empty,
+        ext_requests: empty_ext,
+        ext_replies: empty_ext,
+        delivered_events: empty,
+        device_events: record::Range8 {
+            first: xproto::KEY_PRESS_EVENT,
+            last: xproto::KEY_RELEASE_EVENT,
+        },
+        errors: empty,
+        client_started: false,
+        client_died: false,
+    }
+}
+
+// parse_recorded_keys returns the keycode of the key events recorded, and
+// whether the key was pressed or released
+fn parse_recorded_keys(data: &[u8]) -> Vec<(Keycode, bool)> {
+    data.chunks_exact(EVENT_SIZE)
+        .filter_map(|event| match event[0] {
+            xproto::KEY_PRESS_EVENT => Some((event[1], true)),
+            xproto::KEY_RELEASE_EVENT => Some((event[1], false)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Keymap names the keycodes of the X server after the first keysym they
+// produce, the way the config names keys
+struct Keymap {
+    names: HashMap<Keycode, String>,
+    codes: HashMap<String, Keycode>,
+    // The characters typed with and without shift, when keys are named
+    // after the layout
+    layout: Option<Layout>,
+}
+
+impl Keymap {
+    fn load(conn: &impl Connection, key_names: KeyNames) -> Result<Self, Error> {
+        let setup = conn.setup();
+        let (min_keycode, max_keycode) = (setup.min_keycode, setup.max_keycode);
+        let mapping = conn
+            .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+            .map_err(Error::other)?
+            .reply()
+            .map_err(Error::other)?;
+
+        let keymap = Self::from_keysyms(min_keycode, mapping.keysyms_per_keycode, &mapping.keysyms);
+        Ok(match key_names {
+            KeyNames::Layout => keymap,
+            KeyNames::Position => Self::by_position(),
+        })
+    }
+
+    fn from_keysyms(min_keycode: Keycode, keysyms_per_keycode: u8, keysyms: &[u32]) -> Self {
+        let mut keymap = Keymap {
+            names: HashMap::new(),
+            codes: HashMap::new(),
+            layout: Some(Layout::default()),
+        };
+
+        let keycodes = min_keycode..=Keycode::MAX;
+        for (keycode, keysyms) in keycodes.zip(keysyms.chunks(keysyms_per_keycode.max(1).into())) {
+            if let Some(name) = keysyms.first().and_then(|keysym| keysym_to_name(*keysym)) {
+                let char = |level: usize| {
+                    keysyms
+                        .get(level)
+                        .and_then(|keysym| keysym_to_char(*keysym))
+                };
+                if let Some(layout) = keymap.layout.as_mut() {
+                    layout.insert(&name, char(0), char(1));
+                }
+                keymap.codes.entry(name.clone()).or_insert(keycode);
+                keymap.names.insert(keycode, name);
+            }
+        }
+
+        keymap
+    }
+
+    // by_position names the keys after their position on a US keyboard,
+    // the X11 keycodes are the evdev ones plus 8 on any X server of Linux
+    fn by_position() -> Self {
+        let mut keymap = Keymap {
+            names: HashMap::new(),
+            codes: HashMap::new(),
+            layout: None,
+        };
+
+        for (code, name) in crate::evdev_backend::key_names() {
+            let Ok(keycode) = Keycode::try_from(code + 8) else {
+                continue;
+            };
+            keymap.codes.entry(name.to_string()).or_insert(keycode);
+            keymap.names.insert(keycode, name.to_string());
+        }
+
+        keymap
+    }
+}
+
+fn keysym_to_name(keysym: u32) -> Option<String> {
+    match keysym_to_char(keysym) {
+        Some(' ') => Some("space".to_string()),
+        Some(c) if !c.is_control() => Some(c.to_lowercase().collect()),
+        _ => KEYSYM_NAMES
+            .iter()
+            .find(|(sym, _)| *sym == keysym)
+            .map(|(_, name)| name.to_string()),
+    }
+}
+
+// keysym_to_char returns the character of the keysym, if it types one
+fn keysym_to_char(keysym: u32) -> Option<char> {
+    match keysym {
+        // Latin 1 keysyms are their character
+        0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym),
+        // Any other character is its code point plus 0x1000000
+        0x1000100..=0x110ffff => char::from_u32(keysym - 0x1000000),
+        _ => None,
+    }
+}
+
+// X11 keysyms, named after the keys of the Mac keyboard they sit at: alt
+// is option and the super key is command
+const KEYSYM_NAMES: &[(u32, &str)] = &[
+    (0xff0d, "return"),
+    (0xff09, "tab"),
+    (0xff08, "delete"),
+    (0xff1b, "escape"),
+    (0xffeb, "command"),
+    (0xffec, "rightcommand"),
+    (0xffe1, "shift"),
+    (0xffe2, "rightshift"),
+    (0xffe5, "capslock"),
+    (0xffe9, "option"),
+    (0xffea, "rightoption"),
+    (0xfe03, "rightoption"), // ISO_Level3_Shift, AltGr
+    (0xffe3, "control"),
+    (0xffe4, "rightcontrol"),
+    (0xffbe, "f1"),
+    (0xffbf, "f2"),
+    (0xffc0, "f3"),
+    (0xffc1, "f4"),
+    (0xffc2, "f5"),
+    (0xffc3, "f6"),
+    (0xffc4, "f7"),
+    (0xffc5, "f8"),
+    (0xffc6, "f9"),
+    (0xffc7, "f10"),
+    (0xffc8, "f11"),
+    (0xffc9, "f12"),
+    (0xffca, "f13"),
+    (0xffcb, "f14"),
+    (0xffcc, "f15"),
+    (0xffcd, "f16"),
+    (0xffce, "f17"),
+    (0xffcf, "f18"),
+    (0xffd0, "f19"),
+    (0xffd1, "f20"),
+    (0xffae, "keypad."),
+    (0xffaa, "keypad*"),
+    (0xffab, "keypad+"),
+    (0xff7f, "keypadclear"),
+    (0xffaf, "keypad/"),
+    (0xff8d, "keypadenter"),
+    (0xffad, "keypad-"),
+    (0xffbd, "keypad="),
+    (0xffb0, "keypad0"),
+    (0xffb1, "keypad1"),
+    (0xffb2, "keypad2"),
+    (0xffb3, "keypad3"),
+    (0xffb4, "keypad4"),
+    (0xffb5, "keypad5"),
+    (0xffb6, "keypad6"),
+    (0xffb7, "keypad7"),
+    (0xffb8, "keypad8"),
+    (0xffb9, "keypad9"),
+    (0xff6a, "help"),
+    (0xff63, "help"), // Insert
+    (0xff50, "home"),
+    (0xff55, "pageup"),
+    (0xffff, "forwarddelete"),
+    (0xff57, "end"),
+    (0xff56, "pagedown"),
+    (0xff51, "left"),
+    (0xff53, "right"),
+    (0xff54, "down"),
+    (0xff52, "up"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_event(type_: u8, keycode: Keycode) -> [u8; EVENT_SIZE] {
+        let mut event = [0; EVENT_SIZE];
+        event[0] = type_;
+        event[1] = keycode;
+        event
+    }
+
+    #[test]
+    fn parse_recorded_keys_reads_presses_and_releases() {
+        let mut data = vec![];
+        data.extend(key_event(xproto::KEY_PRESS_EVENT, 64));
+        data.extend(key_event(xproto::MOTION_NOTIFY_EVENT, 0));
+        data.extend(key_event(xproto::KEY_RELEASE_EVENT, 64));
+
+        assert_eq!(parse_recorded_keys(&data), vec![(64, true), (64, false)]);
+    }
+
+    #[test]
+    fn keymap_names_keycodes_after_their_first_keysym() {
+        // Keycodes 8 to 11, two keysyms each: a A, 1 !, Alt_L Meta_L, F18
+        let keysyms = [0x61, 0x41, 0x31, 0x21, 0xffe9, 0xffe7, 0xffcf, 0];
+        let keymap = Keymap::from_keysyms(8, 2, &keysyms);
+
+        assert_eq!(keymap.names[&8], "a");
+        assert_eq!(keymap.names[&9], "1");
+        assert_eq!(keymap.names[&10], "option");
+        assert_eq!(keymap.names[&11], "f18");
+        assert_eq!(keymap.codes["option"], 10);
+
+        let layout = keymap.layout.unwrap();
+        let shifted = KeyEvent {
+            key: "1".to_string(),
+            modifiers: Modifiers {
+                shift: true,
+                ..Modifiers::default()
+            },
+            state: KeyState::Down,
+            timestamp: Instant::now(),
+        };
+        assert_eq!(layout.rename(&shifted).key, "!");
+    }
+
+    #[test]
+    fn keysym_to_name_lowercases_letters() {
+        assert_eq!(keysym_to_name(0x4f).as_deref(), Some("o"));
+        assert_eq!(keysym_to_name(0x20).as_deref(), Some("space"));
+        assert_eq!(keysym_to_name(0x3b).as_deref(), Some(";"));
+        assert_eq!(keysym_to_name(0xc9).as_deref(), Some("é"));
+        assert_eq!(keysym_to_name(0x10004b8).as_deref(), Some("ҹ"));
+        assert_eq!(keysym_to_name(0x1008ff13), None);
+    }
+
+    // Runs the backend against Xvfb with xdotool typing, e.g. with
+    // `cargo test -- --ignored x11`
+    #[test]
+    #[ignore = "needs Xvfb and xdotool"]
+    fn x11_backend_fires_mapping_typed_with_xdotool() {
+        use crate::sequence::KeyStrokeRecorder;
+        use std::process::Command;
+
+        let display = ":87";
+        let mut xvfb = Command::new("Xvfb").arg(display).spawn().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        std::env::set_var("DISPLAY", display);
+
+        let fired = std::env::temp_dir().join("whichkey-x11-test");
+        let _ = std::fs::remove_file(&fired);
+        let config = format!(
+            r#"
+leader_key = "option"
+
+[[groups]]
+name = "Run commands"
+
+  [[groups.mappings]]
+  keys = "rt"
+  kind = "Command"
+  command = "touch {}"
+"#,
+            fired.display()
+        );
+        let recorder = KeyStrokeRecorder::with_config(toml::from_str(&config).unwrap());
+
+        let mut backend = X11Backend::new(KeyNames::Layout);
+        let stop = backend.stop.clone();
+        let running = thread::spawn(move || backend.run(Box::new(recorder)));
+        thread::sleep(Duration::from_millis(500));
+
+        let xdotool = |keys: &[&str]| {
+            let status = Command::new("xdotool")
+                .arg("key")
+                .arg("--delay")
+                .arg("50")
+                .args(keys)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        xdotool(&["alt", "r", "t"]);
+        thread::sleep(Duration::from_millis(500));
+
+        stop.store(true, Ordering::SeqCst);
+        running.join().unwrap().unwrap();
+        xvfb.kill().unwrap();
+        xvfb.wait().unwrap();
+
+        assert!(fired.exists(), "mapping didn't fire");
+    }
+}
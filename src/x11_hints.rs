@@ -0,0 +1,279 @@
+use std::io::Error;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    ConfigureWindowAux, ConnectionExt as _, CreateGCAux, CreateWindowAux, EventMask, Font,
+    Gcontext, Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use crate::hint::{hint_lines, Hint, HintDisplay};
+
+// Space around the text of the popup, in pixels
+const PADDING: u16 = 8;
+
+// How often the popup checks if it has to be redrawn or hidden
+const TICK: Duration = Duration::from_millis(50);
+
+enum Message {
+    Show(Vec<String>, Duration),
+    Hide,
+}
+
+// X11Hints shows the hints in an undecorated window at the bottom of the
+// screen. The window is drawn on its own connection and thread, so showing
+// the hints never holds up the key events.
+pub struct X11Hints {
+    leader_key: String,
+    sender: Sender<Message>,
+    #[cfg_attr(not(test), allow(dead_code))]
+    window: Window,
+}
+
+impl X11Hints {
+    pub fn new(leader_key: &str) -> Result<Self, Error> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(Error::other)?;
+        let popup = Popup::new(conn, screen_num).map_err(Error::other)?;
+        let window = popup.window;
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            if let Err(err) = popup.run(receiver) {
+                log::error!("Hint window failed: {}", err);
+            }
+        });
+
+        Ok(X11Hints {
+            leader_key: leader_key.to_string(),
+            sender,
+            window,
+        })
+    }
+}
+
+impl HintDisplay for X11Hints {
+    fn show(&mut self, keys: &[&str], hints: &[Hint], timeout: Duration) {
+        let lines = hint_lines(&self.leader_key, keys, hints);
+        let _ = self.sender.send(Message::Show(lines, timeout));
+    }
+
+    fn hide(&mut self) {
+        let _ = self.sender.send(Message::Hide);
+    }
+}
+
+struct Popup {
+    conn: RustConnection,
+    window: Window,
+    gc: Gcontext,
+    screen_width: u16,
+    screen_height: u16,
+    char_width: u16,
+    ascent: u16,
+    line_height: u16,
+    lines: Vec<Vec<u8>>,
+    hide_at: Option<Instant>,
+}
+
+impl Popup {
+    fn new(
+        conn: RustConnection,
+        screen_num: usize,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let screen = &conn.setup().roots[screen_num];
+        let (root, black, white) = (screen.root, screen.black_pixel, screen.white_pixel);
+        let (screen_width, screen_height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+        let font: Font = conn.generate_id()?;
+        conn.open_font(font, b"fixed")?.check()?;
+        let metrics = conn.query_font(font)?.reply()?;
+
+        let window = conn.generate_id()?;
+        conn.create_window(
+            x11rb::COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            0,
+            0,
+            1,
+            1,
+            1,
+            WindowClass::INPUT_OUTPUT,
+            x11rb::COPY_FROM_PARENT,
+            &CreateWindowAux::new()
+                .background_pixel(black)
+                .border_pixel(white)
+                .override_redirect(1)
+                .event_mask(EventMask::EXPOSURE),
+        )?;
+
+        let gc = conn.generate_id()?;
+        conn.create_gc(
+            gc,
+            window,
+            &CreateGCAux::new()
+                .foreground(white)
+                .background(black)
+                .font(font),
+        )?;
+        conn.close_font(font)?;
+        conn.flush()?;
+
+        Ok(Popup {
+            window,
+            gc,
+            screen_width,
+            screen_height,
+            char_width: metrics.max_bounds.character_width as u16,
+            ascent: metrics.font_ascent as u16,
+            line_height: (metrics.font_ascent + metrics.font_descent) as u16,
+            lines: vec![],
+            hide_at: None,
+            conn,
+        })
+    }
+
+    fn run(
+        mut self,
+        receiver: Receiver<Message>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            match receiver.recv_timeout(TICK) {
+                Ok(Message::Show(lines, timeout)) => {
+                    self.lines = lines.iter().map(|line| latin1(line)).collect();
+                    self.hide_at = Some(Instant::now() + timeout);
+                    self.place()?;
+                    self.draw()?;
+                }
+                Ok(Message::Hide) => self.hide()?,
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.hide_at.is_some_and(|at| at <= Instant::now()) {
+                        self.hide()?;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            while let Some(event) = self.conn.poll_for_event()? {
+                if let Event::Expose(_) = event {
+                    self.draw()?;
+                }
+            }
+            self.conn.flush()?;
+        }
+    }
+
+    // place sizes the window to the lines, centered at the bottom of the
+    // screen, and maps it
+    fn place(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let columns = self.lines.iter().map(Vec::len).max().unwrap_or_default() as u16;
+        let width = columns * self.char_width + 2 * PADDING;
+        let height = self.lines.len() as u16 * self.line_height + 2 * PADDING;
+        let x = self.screen_width.saturating_sub(width) / 2;
+        let y = self.screen_height.saturating_sub(height + 4 * PADDING);
+
+        self.conn.configure_window(
+            self.window,
+            &ConfigureWindowAux::new()
+                .x(i32::from(x))
+                .y(i32::from(y))
+                .width(u32::from(width))
+                .height(u32::from(height))
+                .stack_mode(x11rb::protocol::xproto::StackMode::ABOVE),
+        )?;
+        self.conn.map_window(self.window)?;
+        Ok(())
+    }
+
+    fn draw(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.hide_at.is_none() {
+            return Ok(());
+        }
+
+        for (i, line) in self.lines.iter().enumerate() {
+            let y = PADDING + i as u16 * self.line_height + self.ascent;
+            self.conn
+                .image_text8(self.window, self.gc, PADDING as i16, y as i16, line)?;
+        }
+        Ok(())
+    }
+
+    fn hide(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.hide_at.take().is_some() {
+            self.conn.unmap_window(self.window)?;
+        }
+        Ok(())
+    }
+}
+
+// latin1 encodes the line for the core fonts, the arrow of the hints
+// becomes "->" and other characters they don't have "?"
+fn latin1(line: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    for c in line.chars() {
+        match c {
+            '→' => bytes.extend(b"->"),
+            c if (c as u32) < 0x100 => bytes.push(c as u8),
+            _ => bytes.push(b'?'),
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11rb::protocol::xproto::MapState;
+
+    #[test]
+    fn latin1_replaces_arrow_and_unknown_characters() {
+        assert_eq!(latin1("f  → Café ✓"), b"f  -> Caf\xe9 ?");
+    }
+
+    // Shows the hints on Xvfb, e.g. with `cargo test -- --ignored x11`
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn x11_hints_map_and_unmap_window() {
+        use std::process::Command;
+
+        let display = ":88";
+        let mut xvfb = Command::new("Xvfb").arg(display).spawn().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        std::env::set_var("DISPLAY", display);
+
+        let mut hints = X11Hints::new("option").unwrap();
+        let (conn, _) = x11rb::connect(None).unwrap();
+        let map_state = |window| {
+            thread::sleep(TICK * 4);
+            conn.get_window_attributes(window)
+                .unwrap()
+                .reply()
+                .unwrap()
+                .map_state
+        };
+
+        let hint = Hint {
+            key: "f".to_string(),
+            label: "Finder".to_string(),
+            is_prefix: false,
+        };
+        hints.show(&["o"], std::slice::from_ref(&hint), Duration::from_secs(10));
+        assert_eq!(map_state(hints.window), MapState::VIEWABLE);
+        hints.hide();
+        assert_eq!(map_state(hints.window), MapState::UNMAPPED);
+
+        // The popup goes away by itself once the sequence timed out
+        hints.show(&[], &[hint], Duration::from_millis(500));
+        assert_eq!(map_state(hints.window), MapState::VIEWABLE);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(map_state(hints.window), MapState::UNMAPPED);
+
+        xvfb.kill().unwrap();
+        xvfb.wait().unwrap();
+    }
+}
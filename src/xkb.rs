@@ -0,0 +1,198 @@
+use std::ffi::CString;
+use std::io::Error;
+use std::ptr;
+
+use xkbcommon_dl::{
+    xkb_context, xkb_context_flags, xkb_keymap, xkb_keymap_compile_flags, xkb_keymap_format,
+    xkb_rule_names, xkbcommon_option, XkbCommon,
+};
+
+use crate::layout::Layout;
+
+// XKB keycodes are the evdev ones plus 8
+const EVDEV_OFFSET: u32 = 8;
+
+// Keymap is an XKB keymap compiled by libxkbcommon, loaded when whichkey
+// starts so it runs without it when keys are named by position
+pub struct Keymap {
+    xkb: &'static XkbCommon,
+    keymap: *mut xkb_keymap,
+}
+
+impl Keymap {
+    // from_names compiles the keymap of the layout and variant, the ones of
+    // $XKB_DEFAULT_LAYOUT and $XKB_DEFAULT_VARIANT when not given
+    pub fn from_names(layout: Option<&str>, variant: Option<&str>) -> Result<Self, Error> {
+        let xkb = library()?;
+        let layout = layout.map(CString::new).transpose()?;
+        let variant = variant.map(CString::new).transpose()?;
+        let names = xkb_rule_names {
+            rules: ptr::null(),
+            model: ptr::null(),
+            layout: layout
+                .as_ref()
+                .map_or(ptr::null(), |layout| layout.as_ptr()),
+            variant: variant
+                .as_ref()
+                .map_or(ptr::null(), |variant| variant.as_ptr()),
+            options: ptr::null(),
+        };
+
+        Self::compile(xkb, |context| unsafe {
+            (xkb.xkb_keymap_new_from_names)(
+                context,
+                &names,
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            )
+        })
+    }
+
+    // from_string compiles a keymap in the text format, like the ones
+    // `xkbcomp -xkb` writes
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn from_string(keymap: &str) -> Result<Self, Error> {
+        let xkb = library()?;
+        let keymap = CString::new(keymap)?;
+
+        Self::compile(xkb, |context| unsafe {
+            (xkb.xkb_keymap_new_from_string)(
+                context,
+                keymap.as_ptr(),
+                xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            )
+        })
+    }
+
+    fn compile(
+        xkb: &'static XkbCommon,
+        new: impl FnOnce(*mut xkb_context) -> *mut xkb_keymap,
+    ) -> Result<Self, Error> {
+        let context = unsafe { (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS) };
+        if context.is_null() {
+            return Err(Error::other("Failed to create the XKB context"));
+        }
+
+        // The keymap keeps the context alive as long as it needs it
+        let keymap = new(context);
+        unsafe { (xkb.xkb_context_unref)(context) };
+        if keymap.is_null() {
+            return Err(Error::other("Failed to compile the XKB keymap"));
+        }
+
+        Ok(Keymap { xkb, keymap })
+    }
+
+    // layout names the keys, given by their evdev code and name, after the
+    // characters the first layout of the keymap types with them
+    pub fn layout<'a>(&self, keys: impl IntoIterator<Item = (u16, &'a str)>) -> Layout {
+        let mut layout = Layout::default();
+        for (code, name) in keys {
+            let keycode = u32::from(code) + EVDEV_OFFSET;
+            layout.insert(name, self.char(keycode, 0), self.char(keycode, 1));
+        }
+
+        layout
+    }
+
+    // char returns the character of the key at the shift level, 0 for the
+    // key alone and 1 with shift
+    fn char(&self, keycode: u32, level: u32) -> Option<char> {
+        let mut keysyms = ptr::null();
+        let count = unsafe {
+            (self.xkb.xkb_keymap_key_get_syms_by_level)(
+                self.keymap,
+                keycode,
+                0,
+                level,
+                &mut keysyms,
+            )
+        };
+        if count < 1 || keysyms.is_null() {
+            return None;
+        }
+
+        let utf32 = unsafe { (self.xkb.xkb_keysym_to_utf32)(*keysyms) };
+        char::from_u32(utf32).filter(|c| *c != '\0')
+    }
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        unsafe { (self.xkb.xkb_keymap_unref)(self.keymap) };
+    }
+}
+
+fn library() -> Result<&'static XkbCommon, Error> {
+    xkbcommon_option().ok_or_else(|| Error::other("Failed to load libxkbcommon.so"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{KeyEvent, KeyState, Modifiers};
+    use std::path::Path;
+    use std::time::Instant;
+
+    // keymap compiles a keymap of tests/keymaps
+    fn keymap(name: &str) -> Keymap {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/keymaps")
+            .join(name);
+        Keymap::from_string(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    // renamed names the evdev keys after the layout, shifted or not
+    fn renamed(layout: &Layout, name: &str, shift: bool) -> String {
+        let event = KeyEvent {
+            key: name.to_string(),
+            modifiers: Modifiers {
+                shift,
+                ..Modifiers::default()
+            },
+            state: KeyState::Down,
+            timestamp: Instant::now(),
+        };
+        let event = layout.rename(&event);
+        crate::keys::token(&event.key, event.modifiers)
+    }
+
+    #[test]
+    fn layout_names_keys_after_azerty_keymap() {
+        // KEY_Q, KEY_A, KEY_SEMICOLON, KEY_1, KEY_LEFTBRACE, KEY_N
+        let keys = [
+            (16, "q"),
+            (30, "a"),
+            (39, ";"),
+            (2, "1"),
+            (26, "["),
+            (49, "n"),
+        ];
+        let layout = keymap("azerty.xkb").layout(keys);
+
+        assert_eq!(renamed(&layout, "q", false), "a");
+        assert_eq!(renamed(&layout, "q", true), "A");
+        assert_eq!(renamed(&layout, "a", false), "q");
+        assert_eq!(renamed(&layout, ";", false), "m");
+        assert_eq!(renamed(&layout, "1", false), "&");
+        assert_eq!(renamed(&layout, "1", true), "1");
+        // A dead key keeps its name
+        assert_eq!(renamed(&layout, "[", false), "[");
+        assert_eq!(renamed(&layout, "n", false), "n");
+    }
+
+    #[test]
+    fn layout_names_keys_after_dvorak_keymap() {
+        // KEY_Q, KEY_W, KEY_E, KEY_S, KEY_Z
+        let keys = [(16, "q"), (17, "w"), (18, "e"), (31, "s"), (44, "z")];
+        let layout = keymap("dvorak.xkb").layout(keys);
+
+        assert_eq!(renamed(&layout, "q", false), "'");
+        assert_eq!(renamed(&layout, "w", false), ",");
+        // "<" can't be written in the keys of a mapping, it stays shifted
+        assert_eq!(renamed(&layout, "w", true), "<S-,>");
+        assert_eq!(renamed(&layout, "e", false), ".");
+        assert_eq!(renamed(&layout, "s", true), "O");
+        assert_eq!(renamed(&layout, "z", false), ";");
+    }
+}